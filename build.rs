@@ -0,0 +1,10 @@
+//! Sets the `has_runtime` cfg when a websocket runtime feature is enabled.
+
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(has_runtime)");
+    if std::env::var_os("CARGO_FEATURE_TOKIO_RUNTIME").is_some()
+        || std::env::var_os("CARGO_FEATURE_ASYNC_STD_RUNTIME").is_some()
+    {
+        println!("cargo:rustc-cfg=has_runtime");
+    }
+}
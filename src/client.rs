@@ -1,22 +1,50 @@
 //! Client struct and functions for interacting with the REST API.
 
+#[cfg(has_runtime)]
+use crate::{
+    filter::EventFilterHandle,
+    reconnect::WebsocketSupervisor,
+    runtime::{self, ConnectStream},
+    socket::{Connection, ConnectionIo, DisconnectReason, Received, WebsocketSession},
+    transport::WebsocketConnector,
+};
 use crate::{
     models::{self, FileMetadata},
     prelude::*,
+    transport::TransportConfig,
+};
+#[cfg(has_runtime)]
+use async_tungstenite::{
+    tungstenite::{self, client::IntoClientRequest, Message},
+    WebSocketStream,
+};
+use futures_util::{
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt},
+    TryStreamExt,
 };
-use async_tungstenite::{tokio::ConnectStream, tungstenite::Message, WebSocketStream};
+#[cfg(has_runtime)]
 use futures_util::{SinkExt, StreamExt};
 use log::{debug, error};
 use reqwest::{
     header::{self, HeaderMap, HeaderValue},
-    Client, Method,
+    multipart::{Form, Part},
+    Client, Method, Response, StatusCode,
 };
 use serde::de::DeserializeOwned;
 use serde_json::{json, Value};
-use std::time::Duration;
-use tokio::{task::JoinHandle, time::sleep};
+use std::{collections::HashMap, io::SeekFrom};
+#[cfg(has_runtime)]
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use url::Url;
 
+/// How long to wait for the server to accept the authentication of a websocket connection.
+#[cfg(has_runtime)]
+const WEBSOCKET_AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Authentication data, either a login_id and password
 /// or a personal access token. Required for being able
 /// to make calls to a Mattermost instance API.
@@ -74,8 +102,22 @@ pub struct Mattermost {
     pub(crate) authentication_data: AuthenticationData,
     pub(crate) client: Client,
     pub(crate) auth_token: Option<String>,
-    #[cfg(feature = "ws-keep-alive")]
+    #[cfg(has_runtime)]
+    pub(crate) websocket: WebsocketSender,
+    #[cfg(has_runtime)]
+    pub(crate) websocket_session: Arc<Mutex<WebsocketSession>>,
+    #[cfg(has_runtime)]
+    pub(crate) reconnection_backoff: BackoffPolicy,
+    #[cfg(has_runtime)]
+    pub(crate) event_filter: EventFilterHandle,
+    #[cfg(has_runtime)]
+    pub(crate) websocket_header_auth: bool,
+    #[cfg(has_runtime)]
+    pub(crate) websocket_connector: Arc<WebsocketConnector>,
+    #[cfg(all(has_runtime, feature = "ws-keep-alive"))]
     pub(crate) ping_interval: std::time::Duration,
+    #[cfg(all(has_runtime, feature = "ws-keep-alive"))]
+    pub(crate) pong_timeout: std::time::Duration,
 }
 
 impl AsRef<Mattermost> for Mattermost {
@@ -115,12 +157,58 @@ impl Mattermost {
             authentication_data,
             client: Client::new(),
             auth_token,
-            #[cfg(feature = "ws-keep-alive")]
+            #[cfg(has_runtime)]
+            websocket: WebsocketSender::default(),
+            #[cfg(has_runtime)]
+            websocket_session: Arc::default(),
+            #[cfg(has_runtime)]
+            reconnection_backoff: BackoffPolicy::default(),
+            #[cfg(has_runtime)]
+            event_filter: EventFilterHandle::default(),
+            #[cfg(has_runtime)]
+            websocket_header_auth: false,
+            #[cfg(has_runtime)]
+            websocket_connector: Arc::default(),
+            #[cfg(all(has_runtime, feature = "ws-keep-alive"))]
             ping_interval: std::time::Duration::from_secs(30),
+            #[cfg(all(has_runtime, feature = "ws-keep-alive"))]
+            pong_timeout: std::time::Duration::from_secs(60),
         })
     }
 
-    #[cfg(feature = "ws-keep-alive")]
+    #[cfg(has_runtime)]
+    /// Changes the delays between websocket reconnection attempts made by `with_reconnection`.
+    ///
+    /// The default starts at 1 second, doubling up to 1 minute, retrying forever.
+    pub fn with_reconnection_backoff(mut self, policy: BackoffPolicy) -> Self {
+        self.reconnection_backoff = policy;
+        self
+    }
+
+    /// Applies proxy, TLS and websocket settings to API requests and websocket connections.
+    ///
+    /// Fails if the settings are invalid, like unparsable certificates.
+    pub fn with_transport(mut self, config: TransportConfig) -> Result<Self, ApiError> {
+        self.client = config.http_client()?;
+        #[cfg(has_runtime)]
+        {
+            self.websocket_connector = Arc::new(config.websocket_connector()?);
+        }
+        Ok(self)
+    }
+
+    #[cfg(has_runtime)]
+    /// Authenticate websocket connections with an `Authorization` header
+    /// on the upgrade request, instead of an authentication challenge.
+    ///
+    /// Servers behind proxies stripping the header need the challenge,
+    /// which is the default.
+    pub fn with_websocket_header_auth(mut self, enabled: bool) -> Self {
+        self.websocket_header_auth = enabled;
+        self
+    }
+
+    #[cfg(all(has_runtime, feature = "ws-keep-alive"))]
     /// Changes the interval between sending ping messages to keep the websocket connection alive.
     ///
     /// The default is 30 seconds.
@@ -129,6 +217,43 @@ impl Mattermost {
         self
     }
 
+    #[cfg(all(has_runtime, feature = "ws-keep-alive"))]
+    /// Changes how long the websocket server can stay silent before the connection is considered dead.
+    ///
+    /// Checked whenever a ping is sent; the connection then ends with
+    /// `ApiError::WebsocketPongTimeout`, so that it can be reconnected.
+    /// The default is 60 seconds.
+    pub fn with_pong_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.pong_timeout = timeout;
+        self
+    }
+
+    #[cfg(has_runtime)]
+    /// Get a handle for sending actions over the websocket connection.
+    ///
+    /// The handle is shared with clones of this struct, and
+    /// works with whichever connection is currently open.
+    pub fn websocket_sender(&self) -> WebsocketSender {
+        self.websocket.clone()
+    }
+
+    #[cfg(has_runtime)]
+    /// Sets the filter selecting which websocket events are dispatched.
+    ///
+    /// The default filter lets all events through.
+    pub fn with_event_filter(self, filter: EventFilter) -> Self {
+        self.event_filter.set(filter);
+        self
+    }
+
+    #[cfg(has_runtime)]
+    /// Handle for changing the websocket event filter at runtime.
+    ///
+    /// The handle is shared with clones of this struct.
+    pub fn event_filter(&self) -> EventFilterHandle {
+        self.event_filter.clone()
+    }
+
     /// Get a session token from the stored login_id and password.
     /// Required when using login_id and password authentication,
     /// before making any calls to the instance API.
@@ -230,25 +355,77 @@ impl Mattermost {
             None => req_builder,
         };
         let resp = self.client.execute(req_builder.build()?).await?;
-        if !resp.status().is_success() {
-            error!(
-                "Got status {} when requesting data from {}",
-                resp.status(),
-                url
-            );
-            let status = resp.status().as_u16();
-            // attempt to get the standard error information out and return that
-            if let Ok(text) = resp.text().await {
-                if let Ok(data) = serde_json::from_str::<MattermostError>(&text) {
-                    return Err(ApiError::MattermostApiError(data));
-                }
+        let resp = Self::check_response(resp, &url).await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Make a multipart POST request to the Mattermost instance API.
+    async fn query_multipart<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        form: Form,
+    ) -> Result<T, ApiError> {
+        let url = self.endpoint_url(endpoint)?;
+
+        debug!("Making multipart POST request to {}", url);
+
+        let mut headers = self.request_headers()?;
+        // the multipart boundary is set by the client
+        headers.remove(header::CONTENT_TYPE);
+        let resp = self
+            .client
+            .post(url.clone())
+            .headers(headers)
+            .multipart(form)
+            .send()
+            .await?;
+        let resp = Self::check_response(resp, &url).await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Make a GET request to the Mattermost instance API, returning the raw response body.
+    async fn query_bytes(
+        &self,
+        endpoint: &str,
+        query: Option<&[(&str, &str)]>,
+    ) -> Result<Vec<u8>, ApiError> {
+        let url = self.endpoint_url(endpoint)?;
+
+        debug!("Making GET request to {} with query {:?}", url, query);
+
+        let resp = self
+            .client
+            .get(url.clone())
+            .headers(self.request_headers()?)
+            .query(query.unwrap_or(&[]))
+            .send()
+            .await?;
+        let resp = Self::check_response(resp, &url).await?;
+        Ok(resp.bytes().await?.to_vec())
+    }
+
+    /// Turn a non-success response into the appropriate error.
+    async fn check_response(resp: Response, url: &Url) -> Result<Response, ApiError> {
+        if resp.status().is_success() {
+            return Ok(resp);
+        }
+        error!(
+            "Got status {} when requesting data from {}",
+            resp.status(),
+            url
+        );
+        let status = resp.status().as_u16();
+        // attempt to get the standard error information out and return that
+        if let Ok(text) = resp.text().await {
+            if let Ok(data) = serde_json::from_str::<MattermostError>(&text) {
+                return Err(ApiError::MattermostApiError(data));
             }
-            // fallback to generic HTTP status code error
-            return Err(ApiError::StatusCodeError(status));
         }
-        Ok(resp.json().await?)
+        // fallback to generic HTTP status code error
+        Err(ApiError::StatusCodeError(status))
     }
 
+    #[cfg(has_runtime)]
     /// Helper-function for connect_to_websocket that convets http schemes to ws equivalent
     fn ws_instance_url(&self) -> Result<Url, ApiError> {
         let mut url = self.instance_url.clone();
@@ -262,16 +439,15 @@ impl Mattermost {
         Ok(url)
     }
 
+    #[cfg(has_runtime)]
     /// Connect to the websocket API on the instance.
     ///
-    /// This method loops, sending messages received from
-    /// the websocket connection to the passed handler. The
-    /// authentication handshake is handled with the
-    /// connection is made, but otherwise no handling of
-    /// messages is currently implemented.
-    ///
-    /// This function is likely to experience a great
-    /// deal of change soon.
+    /// This method loops, sending events received from
+    /// the websocket connection to the passed handler,
+    /// until the connection is closed. The authentication
+    /// handshake is handled when the connection is made;
+    /// `ApiError::WebsocketAuthenticationFailed` is returned
+    /// if the server rejects the token.
     ///
     /// # Example
     ///
@@ -292,137 +468,163 @@ impl Mattermost {
     /// let auth = AuthenticationData::from_password("you@example.com", "password");
     /// let mut api = Mattermost::new("https://your-mattermost-instance.com", auth).unwrap();
     /// api.store_session_token().await.unwrap();
-    /// api.connect_to_websocket(Handler {}).await.unwrap();
+    /// api.connect_to_websocket(&Handler {}).await.unwrap();
     /// # }
     /// ```
     pub async fn connect_to_websocket<H: WebsocketHandler + 'static>(
         &mut self,
         handler: &H,
     ) -> Result<(), ApiError> {
-        let url = self.ws_instance_url()?.join("websocket")?;
-        let (mut stream, _response) = async_tungstenite::tokio::connect_async(url)
-            .await
-            .map_err(Box::new)?;
-        stream
-            .send(Message::Text(serde_json::to_string(&json!({
-              "seq": 1,
-              "action": "authentication_challenge",
-              "data": {
-                "token": self.auth_token.as_ref().unwrap()
-              }
-            }))?))
-            .await
-            .map_err(Box::new)?;
-
-        self.receive_events(stream, handler).await
-    }
-
-    /// connect_to_websocket but consuming self and looping endlessly on errors
-    pub fn with_reconnection<H: WebsocketHandler + 'static>(
-        mut self,
-        handler: H,
-    ) -> JoinHandle<()> {
-        tokio::spawn({
-            async move {
-                loop {
-                    if let Err(err) = self.connect_to_websocket(&handler).await {
-                        error!("Websocket error: {err:?}");
-                    }
-
-                    sleep(Duration::from_secs(5)).await;
-                }
-            }
-        })
+        let connection = self.open_connection().await?;
+        self.dispatch_events(connection, handler).await
     }
 
-    #[cfg(not(feature = "ws-keep-alive"))]
-    async fn receive_events<H: WebsocketHandler + 'static>(
+    #[cfg(has_runtime)]
+    /// Send events from a connection to the handler until it closes.
+    pub(crate) async fn dispatch_events<H: WebsocketHandler + 'static>(
         &self,
-        mut stream: WebSocketStream<ConnectStream>,
+        mut connection: Connection,
         handler: &H,
     ) -> Result<(), ApiError> {
-        loop {
-            if let Some(event) = stream.next().await {
-                let event = event.map_err(|err| {
-                    error!("Error getting websocket message: {err}");
-                    ApiError::WebsocketError(Box::new(err))
-                })?;
-
-                if self.handle_event(handler, event).await? {
-                    break;
+        while let Some(received) = connection.next_received().await {
+            let event = match received {
+                Ok(Received::Event(event)) => event,
+                Ok(Received::Unparsed(text)) => {
+                    handler.on_parse_error(&text).await;
+                    continue;
                 }
+                Err(err) => {
+                    handler.on_error(&err).await;
+                    handler
+                        .on_disconnect(DisconnectReason::Error(err.to_string()))
+                        .await;
+                    return Err(err);
+                }
+            };
+            if let Some(info) = connection.take_connected() {
+                handler.on_connect(info).await;
+            }
+            if let Some(missed) = connection.take_missed_events() {
+                handler.on_missed_events(missed).await;
+            }
+            if connection.matches_filter(&event) {
+                handler.callback(event).await;
             }
         }
-
+        handler.on_disconnect(connection.close_reason()).await;
         Ok(())
     }
 
-    #[cfg(feature = "ws-keep-alive")]
-    async fn receive_events<H: WebsocketHandler + 'static>(
+    #[cfg(has_runtime)]
+    /// Connect to the websocket API on the instance, returning its events as a stream.
+    ///
+    /// The returned sender can be used to send actions and to close the connection.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use futures_util::StreamExt;
+    /// use mattermost_api::prelude::*;
+    ///
+    /// # async fn run(api: Mattermost) {
+    /// let (mut events, sender) = api.connect_events().await.unwrap();
+    /// while let Some(event) = events.next().await {
+    ///     println!("{:?}", event.unwrap());
+    /// }
+    /// # }
+    /// ```
+    pub async fn connect_events(
         &self,
-        mut stream: WebSocketStream<ConnectStream>,
-        handler: &H,
-    ) -> Result<(), ApiError> {
-        let mut ping_interval = tokio::time::interval(self.ping_interval);
+    ) -> Result<(WebsocketEventStream, WebsocketSender), ApiError> {
+        let connection = self.open_connection().await?;
+        Ok((
+            WebsocketEventStream::new(connection),
+            self.websocket_sender(),
+        ))
+    }
 
-        loop {
-            tokio::select! {
-                Some(event) = stream.next() => {
-                    let event = event.map_err(|err| {
-                        error!("Error getting websocket message: {err}");
-                        ApiError::WebsocketError(Box::new(err))
-                    })?;
-
-                    if self.handle_event(handler, event).await? {
-                        break;
-                    }
-                },
-                _ = ping_interval.tick() => {
-                    if let Err(err) = stream.send(Message::Ping(vec![])).await {
-                        error!("Error sending Ping message through websocket: {err}");
-                    }
-                }
+    #[cfg(has_runtime)]
+    /// Open and authenticate a websocket connection.
+    ///
+    /// If a connection was opened before, the server is asked to
+    /// resume it and replay the events sent since.
+    pub(crate) async fn open_connection(&self) -> Result<Connection, ApiError> {
+        let mut url = self.ws_instance_url()?.join("websocket")?;
+        {
+            let session = self.websocket_session.lock().unwrap();
+            if let Some(connection_id) = &session.connection_id {
+                url.query_pairs_mut()
+                    .append_pair("connection_id", connection_id)
+                    .append_pair("sequence_number", &session.next_seq.to_string());
             }
         }
+        let token = self.auth_token.as_ref().ok_or(ApiError::MissingAuthToken)?;
 
-        Ok(())
-    }
-
-    /// Internal method to aplly the users handler to text events.
-    ///
-    /// Returns true if the connection is closing.
-    async fn handle_event<H: WebsocketHandler + 'static>(
-        &self,
-        handler: &H,
-        message: Message,
-    ) -> Result<bool, ApiError> {
-        match message {
-            Message::Text(text) if text.contains("seq_reply") => {
-                // for now, replies are not sent to the handler
-                debug!("Reply text message received. Skipping.");
-                Ok(false)
+        let mut request = url.as_str().into_client_request().map_err(Box::new)?;
+        if self.websocket_header_auth {
+            request.headers_mut().insert(
+                header::AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {token}"))?,
+            );
+        }
+        let mut stream = match self.websocket_connector.connect(&url, request).await {
+            Err(ApiError::WebsocketError(err))
+                if matches!(&*err, tungstenite::Error::Http(response)
+                    if response.status() == StatusCode::UNAUTHORIZED) =>
+            {
+                return Err(ApiError::WebsocketAuthenticationFailed(
+                    "the token was rejected".to_string(),
+                ));
             }
-            Message::Text(text) => {
-                debug!("Non-reply text message received. Calling handler.");
+            result => result?,
+        };
 
-                let as_struct = serde_json::from_str(&text).map_err(|err| {
-                    error!("Could not parse websocket event JSON: {err}");
-                    ApiError::JsonProcessingError(err)
-                })?;
+        // with header authentication, the upgrade succeeding means the token was accepted,
+        // and no hello is sent when the session is resumed
+        let mut received = Vec::new();
+        if !self.websocket_header_auth {
+            let seq = self.websocket.next_seq();
+            stream
+                .send(Message::Text(serde_json::to_string(&json!({
+                  "seq": seq,
+                  "action": "authentication_challenge",
+                  "data": {
+                    "token": token
+                  }
+                }))?))
+                .await
+                .map_err(Box::new)?;
+            received = runtime::timeout(
+                WEBSOCKET_AUTH_TIMEOUT,
+                wait_for_authentication(&mut stream, seq),
+            )
+            .await??;
+        }
 
-                handler.callback(as_struct).await;
+        let io = ConnectionIo::new(
+            stream,
+            received,
+            self.websocket.clone(),
+            self.websocket_session.clone(),
+        );
+        #[cfg(feature = "ws-keep-alive")]
+        let io = io.with_keep_alive(self.ping_interval, self.pong_timeout);
 
-                Ok(false)
-            }
-            Message::Close(_) => {
-                debug!("Close message received.");
-                Ok(true)
-            }
-            message => {
-                debug!("Non-text, non-close message received: {message:#?}");
-                Ok(false)
-            }
-        }
+        Ok(Connection::spawn(io, self.event_filter.clone()))
+    }
+
+    #[cfg(has_runtime)]
+    /// connect_to_websocket but consuming self and reconnecting on errors.
+    ///
+    /// The returned supervisor reports the connection state and
+    /// can shut the connection down. Delays between reconnection
+    /// attempts follow the policy set with `with_reconnection_backoff`.
+    pub fn with_reconnection<H: WebsocketHandler + 'static>(
+        self,
+        handler: H,
+    ) -> WebsocketSupervisor {
+        let policy = self.reconnection_backoff.clone();
+        WebsocketSupervisor::spawn(self, handler, policy)
     }
 
     // ===========================================================================================
@@ -435,6 +637,11 @@ impl Mattermost {
             .await
     }
 
+    /// Get the user the client is authenticated as.
+    pub async fn get_me(&self) -> Result<models::User, ApiError> {
+        self.query("GET", "users/me", None, None).await
+    }
+
     /// Get users of a channel
     pub async fn get_channel_users(&self, channel_id: &str) -> Result<Vec<models::User>, ApiError> {
         self.query(
@@ -453,15 +660,40 @@ impl Mattermost {
         filename: &str,
         content: &[u8],
     ) -> Result<models::FileUploadResponse, ApiError> {
-        self.query(
-            "POST",
-            "files",
-            Some(&[("channel_id", channel_id), ("filename", filename)]),
-            Some(content),
+        self.upload_files(
+            channel_id,
+            vec![models::UploadFile::new(filename, content.to_vec())],
         )
         .await
     }
 
+    /// Upload several files to a channel in a single multipart request.
+    ///
+    /// The returned `file_infos` are in the same order as `files`. If any file
+    /// has a client id, every file is sent with one (empty if unset) and they
+    /// are echoed back in `client_ids`.
+    pub async fn upload_files(
+        &self,
+        channel_id: &str,
+        files: Vec<models::UploadFile>,
+    ) -> Result<models::FileUploadResponse, ApiError> {
+        let send_client_ids = files.iter().any(|file| file.client_id.is_some());
+        // the server expects the channel id first and each client id before its file
+        let mut form = Form::new().text("channel_id", channel_id.to_string());
+        for file in files {
+            if send_client_ids {
+                form = form.text("client_ids", file.client_id.unwrap_or_default());
+            }
+            let mut part = Part::bytes(file.content).file_name(file.filename);
+            if let Some(mime_type) = file.mime_type {
+                part = part.mime_str(&mime_type)?;
+            }
+            form = form.part("files", part);
+        }
+
+        self.query_multipart("files", form).await
+    }
+
     /// Get the metadata of a file
     pub async fn get_file_info(&self, file_id: &str) -> Result<FileMetadata, ApiError> {
         self.query("GET", &format!("files/{file_id}/info"), None, None)
@@ -469,34 +701,64 @@ impl Mattermost {
     }
 
     /// Get the contents of a file
+    ///
+    /// The whole file is held in memory; use `get_file_stream`
+    /// or `download_file_to` for large files.
     pub async fn get_file(&self, file_id: &str) -> Result<Vec<u8>, ApiError> {
+        self.query_bytes(&format!("files/{file_id}"), None).await
+    }
+
+    /// Get the contents of a file as a stream of chunks, without buffering it in memory.
+    pub async fn get_file_stream(&self, file_id: &str) -> Result<models::FileDownload, ApiError> {
         let url = self.endpoint_url(&format!("files/{file_id}"))?;
 
+        debug!("Making streaming GET request to {}", url);
+
         let resp = self
             .client
             .get(url.clone())
             .headers(self.request_headers()?)
             .send()
             .await?;
+        let resp = Self::check_response(resp, &url).await?;
 
-        if !resp.status().is_success() {
-            error!(
-                "Got status {} when requesting data from {}",
-                resp.status(),
-                url
-            );
-            let status = resp.status().as_u16();
-            // attempt to get the standard error information out and return that
-            if let Ok(text) = resp.text().await {
-                if let Ok(data) = serde_json::from_str::<MattermostError>(&text) {
-                    return Err(ApiError::MattermostApiError(data));
-                }
-            }
-            // fallback to generic HTTP status code error
-            return Err(ApiError::StatusCodeError(status));
-        }
+        let content_type = resp
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|value| value.to_str().map(String::from))
+            .transpose()?;
+        let filename = resp
+            .headers()
+            .get(header::CONTENT_DISPOSITION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(content_disposition_filename);
+
+        Ok(models::FileDownload {
+            content_length: resp.content_length(),
+            content_type,
+            filename,
+            stream: Box::pin(resp.bytes_stream().map_err(ApiError::from)),
+        })
+    }
 
-        Ok(resp.bytes().await?.into_iter().collect())
+    #[cfg(has_runtime)]
+    /// Download a file to `path`, creating or truncating it.
+    ///
+    /// Returns the number of bytes written.
+    pub async fn download_file_to(
+        &self,
+        file_id: &str,
+        path: impl AsRef<Path>,
+    ) -> Result<u64, ApiError> {
+        let mut download = self.get_file_stream(file_id).await?;
+        let mut file = runtime::File::create(path).await?;
+        let mut written = 0;
+        while let Some(chunk) = download.stream.try_next().await? {
+            file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        file.flush().await?;
+        Ok(written)
     }
 
     /// Execute a command
@@ -674,91 +936,2627 @@ impl Mattermost {
         self.query("GET", &format!("teams/{}/channels", team_id), None, None)
             .await
     }
-}
-
-#[cfg(test)]
-mod url_tests {
-    use super::{AuthenticationData, Mattermost};
-    use crate::errors::ApiError;
 
-    impl PartialEq for ApiError {
-        fn eq(&self, other: &Self) -> bool {
-            self.to_string() == other.to_string()
+    /// Get a page of groups, optionally filtered by a search term.
+    ///
+    /// Requires the "list_groups" permission.
+    pub async fn get_groups(
+        &self,
+        q: Option<&str>,
+        page: Option<u64>,
+        per_page: Option<u64>,
+        include_member_count: Option<bool>,
+    ) -> Result<Vec<models::Group>, ApiError> {
+        let mut query: Vec<(&str, String)> = Vec::new();
+        if let Some(v) = q {
+            query.push(("q", v.into()));
+        }
+        if let Some(v) = page {
+            query.push(("page", v.to_string()));
+        }
+        if let Some(v) = per_page {
+            query.push(("per_page", v.to_string()));
+        }
+        if let Some(v) = include_member_count {
+            query.push(("include_member_count", v.to_string()));
         }
+        let query: Vec<(&str, &str)> = query.iter().map(|(a, b)| (*a, &**b)).collect();
+        self.query("GET", "groups", Some(&query), None).await
     }
 
-    #[test]
-    fn invalid_instance_url_fails_fast() {
-        let Err(err) = Mattermost::new("herp derp", AuthenticationData::from_access_token("x"))
-        else {
-            panic!("Expected an error")
-        };
-
-        assert_eq!(err, ApiError::UrlError(url::ParseError::EmptyHost))
+    /// Get a group's information.
+    pub async fn get_group(&self, group_id: &str) -> Result<models::Group, ApiError> {
+        self.query("GET", &format!("groups/{group_id}"), None, None)
+            .await
     }
 
-    #[test]
-    fn api_v4_path_is_added_by_default() {
-        let client = Mattermost::new(
-            "http://www.mattermost.com",
-            AuthenticationData::from_access_token("x"),
-        )
-        .expect("This should work");
+    /// Create a custom group.
+    ///
+    /// Requires the "create_custom_group" permission.
+    pub async fn create_group(
+        &self,
+        group: &models::CreateGroup,
+    ) -> Result<models::Group, ApiError> {
+        let body = serde_json::to_string_pretty(group)?;
 
-        assert_eq!(
-            client.instance_url.as_str(),
-            "http://www.mattermost.com/api/v4/"
-        )
+        self.query("POST", "groups", None, Some(body.as_bytes()))
+            .await
     }
 
-    #[test]
-    fn api_path_can_be_overridden() {
-        let client = Mattermost::new(
-            "http://www.mattermost.com/ipa/v5/",
-            AuthenticationData::from_access_token("x"),
-        )
-        .expect("This should work");
+    /// Patch a group.
+    pub async fn patch_group(
+        &self,
+        group_id: &str,
+        group: &models::PatchGroup,
+    ) -> Result<models::Group, ApiError> {
+        let body = serde_json::to_string_pretty(group)?;
 
-        assert_eq!(
-            client.instance_url.as_str(),
-            "http://www.mattermost.com/ipa/v5/"
+        self.query(
+            "PUT",
+            &format!("groups/{group_id}/patch"),
+            None,
+            Some(body.as_bytes()),
         )
+        .await
     }
 
-    #[test]
-    fn http_urls_are_properly_converted_to_ws_urls() {
-        let http_client = Mattermost::new(
-            "http://www.mattermost.com",
-            AuthenticationData::from_access_token("x"),
-        )
-        .unwrap();
-        assert_eq!(
-            http_client.ws_instance_url().unwrap().as_str(),
-            "ws://www.mattermost.com/api/v4/"
-        );
+    /// Delete a custom group.
+    pub async fn delete_group(&self, group_id: &str) -> Result<models::StatusResponse, ApiError> {
+        self.query("DELETE", &format!("groups/{group_id}"), None, None)
+            .await
+    }
 
-        let https_client = Mattermost::new(
-            "https://www.mattermost.com/",
-            AuthenticationData::from_access_token("x"),
+    /// Get the members of a group.
+    pub async fn get_group_members(
+        &self,
+        group_id: &str,
+        page: Option<u64>,
+        per_page: Option<u64>,
+    ) -> Result<models::GroupMembers, ApiError> {
+        let mut query: Vec<(&str, String)> = Vec::new();
+        if let Some(v) = page {
+            query.push(("page", v.to_string()));
+        }
+        if let Some(v) = per_page {
+            query.push(("per_page", v.to_string()));
+        }
+        let query: Vec<(&str, &str)> = query.iter().map(|(a, b)| (*a, &**b)).collect();
+        self.query(
+            "GET",
+            &format!("groups/{group_id}/members"),
+            Some(&query),
+            None,
         )
-        .unwrap();
-        assert_eq!(
-            https_client.ws_instance_url().unwrap().as_str(),
-            "wss://www.mattermost.com/api/v4/"
-        );
+        .await
     }
 
-    #[test]
-    fn endpoint_urls_are_joined_as_expected() {
+    /// Add users to a custom group.
+    pub async fn add_group_members(
+        &self,
+        group_id: &str,
+        user_ids: Vec<String>,
+    ) -> Result<Vec<models::GroupMember>, ApiError> {
+        let body = serde_json::to_string_pretty(&models::GroupMemberIds { user_ids })?;
+
+        self.query(
+            "POST",
+            &format!("groups/{group_id}/members"),
+            None,
+            Some(body.as_bytes()),
+        )
+        .await
+    }
+
+    /// Remove users from a custom group.
+    pub async fn delete_group_members(
+        &self,
+        group_id: &str,
+        user_ids: Vec<String>,
+    ) -> Result<Vec<models::GroupMember>, ApiError> {
+        let body = serde_json::to_string_pretty(&models::GroupMemberIds { user_ids })?;
+
+        self.query(
+            "DELETE",
+            &format!("groups/{group_id}/members"),
+            None,
+            Some(body.as_bytes()),
+        )
+        .await
+    }
+
+    /// Get the groups a user is a member of.
+    pub async fn get_user_groups(&self, user_id: &str) -> Result<Vec<models::Group>, ApiError> {
+        self.query("GET", &format!("users/{user_id}/groups"), None, None)
+            .await
+    }
+
+    /// Get the groups linked to a team.
+    pub async fn get_team_groups(
+        &self,
+        team_id: &str,
+    ) -> Result<models::GroupsWithCount, ApiError> {
+        self.query("GET", &format!("teams/{team_id}/groups"), None, None)
+            .await
+    }
+
+    /// Get the groups linked to a channel.
+    pub async fn get_channel_groups(
+        &self,
+        channel_id: &str,
+    ) -> Result<models::GroupsWithCount, ApiError> {
+        self.query("GET", &format!("channels/{channel_id}/groups"), None, None)
+            .await
+    }
+
+    /// Get the teams or channels a group is linked to.
+    pub async fn get_group_syncables(
+        &self,
+        group_id: &str,
+        syncable_type: models::GroupSyncableType,
+    ) -> Result<Vec<models::GroupSyncable>, ApiError> {
+        self.query(
+            "GET",
+            &format!("groups/{group_id}/{}", syncable_type.as_path()),
+            None,
+            None,
+        )
+        .await
+    }
+
+    /// Link a group to a team or channel.
+    ///
+    /// If `auto_add` is set, group members are added to the team or channel automatically.
+    pub async fn link_group_syncable(
+        &self,
+        group_id: &str,
+        syncable_type: models::GroupSyncableType,
+        syncable_id: &str,
+        auto_add: bool,
+    ) -> Result<models::GroupSyncable, ApiError> {
+        let body = serde_json::to_string_pretty(&models::LinkGroupSyncable { auto_add })?;
+
+        self.query(
+            "POST",
+            &format!(
+                "groups/{group_id}/{}/{syncable_id}/link",
+                syncable_type.as_path()
+            ),
+            None,
+            Some(body.as_bytes()),
+        )
+        .await
+    }
+
+    /// Unlink a group from a team or channel.
+    pub async fn unlink_group_syncable(
+        &self,
+        group_id: &str,
+        syncable_type: models::GroupSyncableType,
+        syncable_id: &str,
+    ) -> Result<models::StatusResponse, ApiError> {
+        self.query(
+            "DELETE",
+            &format!(
+                "groups/{group_id}/{}/{syncable_id}/link",
+                syncable_type.as_path()
+            ),
+            None,
+            None,
+        )
+        .await
+    }
+
+    /// Check the health of the server.
+    ///
+    /// If `get_server_status` is set, the database and file store
+    /// are checked as well and their status is included.
+    pub async fn ping(&self, get_server_status: bool) -> Result<models::SystemStatus, ApiError> {
+        let get_server_status = get_server_status.to_string();
+        self.query(
+            "GET",
+            "system/ping",
+            Some(&[("get_server_status", &get_server_status)]),
+            None,
+        )
+        .await
+    }
+
+    /// Get the server configuration.
+    ///
+    /// The configuration is left untyped: it has hundreds of settings that
+    /// change between server versions, and `update_config` replaces it
+    /// whole, so a typed model would drop the settings it does not know.
+    /// The sections used with this crate can be read typed with
+    /// `get_service_settings`, `get_file_settings` and `get_message_export_settings`.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn get_config(&self) -> Result<Value, ApiError> {
+        self.query("GET", "config", None, None).await
+    }
+
+    /// Get the service settings from the server configuration.
+    ///
+    /// Returns `None` if the configuration has no such section.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn get_service_settings(&self) -> Result<Option<models::ServiceSettings>, ApiError> {
+        self.get_config_section("ServiceSettings").await
+    }
+
+    /// Get the file settings from the server configuration.
+    ///
+    /// Returns `None` if the configuration has no such section.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn get_file_settings(&self) -> Result<Option<models::FileSettings>, ApiError> {
+        self.get_config_section("FileSettings").await
+    }
+
+    /// Get a section of the server configuration, or `None` if it is missing.
+    async fn get_config_section<T: DeserializeOwned>(
+        &self,
+        section: &str,
+    ) -> Result<Option<T>, ApiError> {
+        let mut config = self.get_config().await?;
+        let settings = config.get_mut(section).map(Value::take).unwrap_or_default();
+        Ok(serde_json::from_value(settings)?)
+    }
+
+    /// Replace the server configuration, returning the updated configuration.
+    ///
+    /// Settings missing from `config` are reset, so start from `get_config`.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn update_config(&self, config: &Value) -> Result<Value, ApiError> {
+        let body = serde_json::to_string_pretty(config)?;
+
+        self.query("PUT", "config", None, Some(body.as_bytes()))
+            .await
+    }
+
+    /// Patch the server configuration, returning the updated configuration.
+    ///
+    /// Only the settings present in `patch` are changed.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn patch_config(&self, patch: &Value) -> Result<Value, ApiError> {
+        let body = serde_json::to_string_pretty(patch)?;
+
+        self.query("PUT", "config/patch", None, Some(body.as_bytes()))
+            .await
+    }
+
+    /// Reload the server configuration from its backing store.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn reload_config(&self) -> Result<models::StatusResponse, ApiError> {
+        self.query("POST", "config/reload", None, None).await
+    }
+
+    /// Get the subset of the server configuration needed by clients.
+    pub async fn get_client_config(&self) -> Result<HashMap<String, String>, ApiError> {
+        self.query("GET", "config/client", Some(&[("format", "old")]), None)
+            .await
+    }
+
+    /// Get the subset of the server license needed by clients.
+    pub async fn get_client_license(&self) -> Result<HashMap<String, String>, ApiError> {
+        self.query("GET", "license/client", Some(&[("format", "old")]), None)
+            .await
+    }
+
+    /// Get a page of server log lines.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn get_logs(
+        &self,
+        page: Option<u64>,
+        logs_per_page: Option<u64>,
+    ) -> Result<Vec<String>, ApiError> {
+        let mut query: Vec<(&str, String)> = Vec::new();
+        if let Some(v) = page {
+            query.push(("page", v.to_string()));
+        }
+        if let Some(v) = logs_per_page {
+            query.push(("logs_per_page", v.to_string()));
+        }
+        let query: Vec<(&str, &str)> = query.iter().map(|(a, b)| (*a, &**b)).collect();
+        self.query("GET", "logs", Some(&query), None).await
+    }
+
+    /// Get the product notices that apply to the current user in a team.
+    pub async fn get_notices(
+        &self,
+        team_id: &str,
+        client_version: &str,
+        client: &str,
+        locale: Option<&str>,
+    ) -> Result<Vec<models::Notice>, ApiError> {
+        let mut query = vec![("clientVersion", client_version), ("client", client)];
+        if let Some(v) = locale {
+            query.push(("locale", v));
+        }
+        self.query(
+            "GET",
+            &format!("system/notices/{team_id}"),
+            Some(&query),
+            None,
+        )
+        .await
+    }
+
+    /// Mark product notices as viewed by the current user.
+    pub async fn mark_notices_viewed(
+        &self,
+        notice_ids: &[&str],
+    ) -> Result<models::StatusResponse, ApiError> {
+        let body = serde_json::to_string_pretty(notice_ids)?;
+
+        self.query("PUT", "system/notices/view", None, Some(body.as_bytes()))
+            .await
+    }
+
+    /// Create a new job.
+    ///
+    /// Requires the "manage_jobs" permission.
+    pub async fn create_job(&self, job: &models::CreateJob) -> Result<models::Job, ApiError> {
+        let body = serde_json::to_string_pretty(job)?;
+
+        self.query("POST", "jobs", None, Some(body.as_bytes()))
+            .await
+    }
+
+    /// Get a page of jobs of all types, most recent first.
+    ///
+    /// Requires the "manage_jobs" permission.
+    pub async fn get_jobs(
+        &self,
+        page: Option<u64>,
+        per_page: Option<u64>,
+    ) -> Result<Vec<models::Job>, ApiError> {
+        let mut query: Vec<(&str, String)> = Vec::new();
+        if let Some(v) = page {
+            query.push(("page", v.to_string()));
+        }
+        if let Some(v) = per_page {
+            query.push(("per_page", v.to_string()));
+        }
+        let query: Vec<(&str, &str)> = query.iter().map(|(a, b)| (*a, &**b)).collect();
+        self.query("GET", "jobs", Some(&query), None).await
+    }
+
+    /// Get a page of jobs of a single type, most recent first.
+    ///
+    /// Requires the "manage_jobs" permission.
+    pub async fn get_jobs_by_type(
+        &self,
+        job_type: &models::JobType,
+        page: Option<u64>,
+        per_page: Option<u64>,
+    ) -> Result<Vec<models::Job>, ApiError> {
+        let mut query: Vec<(&str, String)> = Vec::new();
+        if let Some(v) = page {
+            query.push(("page", v.to_string()));
+        }
+        if let Some(v) = per_page {
+            query.push(("per_page", v.to_string()));
+        }
+        let query: Vec<(&str, &str)> = query.iter().map(|(a, b)| (*a, &**b)).collect();
+        self.query(
+            "GET",
+            &format!("jobs/type/{}", job_type.as_str()),
+            Some(&query),
+            None,
+        )
+        .await
+    }
+
+    /// Get a job.
+    ///
+    /// Requires the "manage_jobs" permission.
+    pub async fn get_job(&self, job_id: &str) -> Result<models::Job, ApiError> {
+        self.query("GET", &format!("jobs/{job_id}"), None, None)
+            .await
+    }
+
+    /// Request cancellation of a job.
+    ///
+    /// Requires the "manage_jobs" permission.
+    pub async fn cancel_job(&self, job_id: &str) -> Result<models::StatusResponse, ApiError> {
+        self.query("POST", &format!("jobs/{job_id}/cancel"), None, None)
+            .await
+    }
+
+    /// Download the results of a finished job, such as a compliance export.
+    ///
+    /// Requires the "manage_jobs" permission.
+    pub async fn download_job(&self, job_id: &str) -> Result<Vec<u8>, ApiError> {
+        self.query_bytes(&format!("jobs/{job_id}/download"), None)
+            .await
+    }
+
+    #[cfg(has_runtime)]
+    /// Poll a job every `poll_interval` until it reaches a terminal status.
+    ///
+    /// The returned job may have failed or been canceled; check its `status`.
+    /// Returns `ApiError::Timeout` if the job is still running after `timeout`.
+    pub async fn wait_for_job(
+        &self,
+        job_id: &str,
+        poll_interval: Duration,
+        timeout: Duration,
+    ) -> Result<models::Job, ApiError> {
+        runtime::timeout(timeout, async {
+            loop {
+                let job = self.get_job(job_id).await?;
+                if job.status.is_terminal() {
+                    return Ok(job);
+                }
+                debug!("Job {job_id} is {:?}, polling again", job.status);
+                runtime::sleep(poll_interval).await;
+            }
+        })
+        .await?
+    }
+
+    /// Upload a plugin bundle (a `.tar.gz` archive).
+    ///
+    /// If `force` is set, an existing plugin with the same id is replaced.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn upload_plugin(
+        &self,
+        bundle: Vec<u8>,
+        force: bool,
+    ) -> Result<models::PluginManifest, ApiError> {
+        let form = Form::new()
+            .part("plugin", Part::bytes(bundle).file_name("plugin.tar.gz"))
+            .text("force", force.to_string());
+
+        self.query_multipart("plugins", form).await
+    }
+
+    /// Download a plugin bundle from a URL and install it.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn install_plugin_from_url(
+        &self,
+        plugin_download_url: &str,
+        force: bool,
+    ) -> Result<models::PluginManifest, ApiError> {
+        let force = force.to_string();
+        self.query(
+            "POST",
+            "plugins/install_from_url",
+            Some(&[
+                ("plugin_download_url", plugin_download_url),
+                ("force", &force),
+            ]),
+            None,
+        )
+        .await
+    }
+
+    /// Get the manifests of all active and inactive plugins.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn get_plugins(&self) -> Result<models::Plugins, ApiError> {
+        self.query("GET", "plugins", None, None).await
+    }
+
+    /// Get the status of every plugin on every server of the cluster.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn get_plugin_statuses(&self) -> Result<Vec<models::PluginStatus>, ApiError> {
+        self.query("GET", "plugins/statuses", None, None).await
+    }
+
+    /// Enable a plugin.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn enable_plugin(&self, plugin_id: &str) -> Result<models::StatusResponse, ApiError> {
+        self.query("POST", &format!("plugins/{plugin_id}/enable"), None, None)
+            .await
+    }
+
+    /// Disable a plugin.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn disable_plugin(
+        &self,
+        plugin_id: &str,
+    ) -> Result<models::StatusResponse, ApiError> {
+        self.query("POST", &format!("plugins/{plugin_id}/disable"), None, None)
+            .await
+    }
+
+    /// Remove a plugin.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn remove_plugin(&self, plugin_id: &str) -> Result<models::StatusResponse, ApiError> {
+        self.query("DELETE", &format!("plugins/{plugin_id}"), None, None)
+            .await
+    }
+
+    /// Get the manifests of active plugins that have a webapp component.
+    pub async fn get_webapp_plugins(&self) -> Result<Vec<models::PluginManifest>, ApiError> {
+        self.query("GET", "plugins/webapp", None, None).await
+    }
+
+    /// Get a page of plugins available on the marketplace.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn get_marketplace_plugins(
+        &self,
+        filter: Option<&str>,
+        page: Option<u64>,
+        per_page: Option<u64>,
+        local_only: Option<bool>,
+    ) -> Result<Vec<models::MarketplacePlugin>, ApiError> {
+        let mut query: Vec<(&str, String)> = Vec::new();
+        if let Some(v) = filter {
+            query.push(("filter", v.into()));
+        }
+        if let Some(v) = page {
+            query.push(("page", v.to_string()));
+        }
+        if let Some(v) = per_page {
+            query.push(("per_page", v.to_string()));
+        }
+        if let Some(v) = local_only {
+            query.push(("local_only", v.to_string()));
+        }
+        let query: Vec<(&str, &str)> = query.iter().map(|(a, b)| (*a, &**b)).collect();
+        self.query("GET", "plugins/marketplace", Some(&query), None)
+            .await
+    }
+
+    /// Install a plugin from the marketplace.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn install_marketplace_plugin(
+        &self,
+        plugin: &models::InstallMarketplacePlugin,
+    ) -> Result<models::PluginManifest, ApiError> {
+        let body = serde_json::to_string_pretty(plugin)?;
+
+        self.query("POST", "plugins/marketplace", None, Some(body.as_bytes()))
+            .await
+    }
+
+    /// Get the global data retention policy.
+    pub async fn get_global_retention_policy(
+        &self,
+    ) -> Result<models::GlobalRetentionPolicy, ApiError> {
+        self.query("GET", "data_retention/policy", None, None).await
+    }
+
+    /// Get the number of granular data retention policies.
+    ///
+    /// Requires the "sysconsole_read_compliance_data_retention" permission.
+    pub async fn get_retention_policies_count(
+        &self,
+    ) -> Result<models::RetentionPolicyCount, ApiError> {
+        self.query("GET", "data_retention/policies_count", None, None)
+            .await
+    }
+
+    /// Get a page of granular data retention policies.
+    ///
+    /// Requires the "sysconsole_read_compliance_data_retention" permission.
+    pub async fn get_retention_policies(
+        &self,
+        page: Option<u64>,
+        per_page: Option<u64>,
+    ) -> Result<models::RetentionPolicies, ApiError> {
+        let mut query: Vec<(&str, String)> = Vec::new();
+        if let Some(v) = page {
+            query.push(("page", v.to_string()));
+        }
+        if let Some(v) = per_page {
+            query.push(("per_page", v.to_string()));
+        }
+        let query: Vec<(&str, &str)> = query.iter().map(|(a, b)| (*a, &**b)).collect();
+        self.query("GET", "data_retention/policies", Some(&query), None)
+            .await
+    }
+
+    /// Get a granular data retention policy.
+    ///
+    /// Requires the "sysconsole_read_compliance_data_retention" permission.
+    pub async fn get_retention_policy(
+        &self,
+        policy_id: &str,
+    ) -> Result<models::RetentionPolicy, ApiError> {
+        self.query(
+            "GET",
+            &format!("data_retention/policies/{policy_id}"),
+            None,
+            None,
+        )
+        .await
+    }
+
+    /// Create a granular data retention policy.
+    ///
+    /// Requires the "sysconsole_write_compliance_data_retention" permission.
+    pub async fn create_retention_policy(
+        &self,
+        policy: &models::CreateRetentionPolicy,
+    ) -> Result<models::RetentionPolicy, ApiError> {
+        let body = serde_json::to_string_pretty(policy)?;
+
+        self.query(
+            "POST",
+            "data_retention/policies",
+            None,
+            Some(body.as_bytes()),
+        )
+        .await
+    }
+
+    /// Patch a granular data retention policy.
+    ///
+    /// Requires the "sysconsole_write_compliance_data_retention" permission.
+    pub async fn patch_retention_policy(
+        &self,
+        policy_id: &str,
+        policy: &models::PatchRetentionPolicy,
+    ) -> Result<models::RetentionPolicy, ApiError> {
+        let body = serde_json::to_string_pretty(policy)?;
+
+        self.query(
+            "PATCH",
+            &format!("data_retention/policies/{policy_id}"),
+            None,
+            Some(body.as_bytes()),
+        )
+        .await
+    }
+
+    /// Delete a granular data retention policy.
+    ///
+    /// Requires the "sysconsole_write_compliance_data_retention" permission.
+    pub async fn delete_retention_policy(
+        &self,
+        policy_id: &str,
+    ) -> Result<models::StatusResponse, ApiError> {
+        self.query(
+            "DELETE",
+            &format!("data_retention/policies/{policy_id}"),
+            None,
+            None,
+        )
+        .await
+    }
+
+    /// Get a page of the teams a granular data retention policy applies to.
+    ///
+    /// Requires the "sysconsole_read_compliance_data_retention" permission.
+    pub async fn get_retention_policy_teams(
+        &self,
+        policy_id: &str,
+        page: Option<u64>,
+        per_page: Option<u64>,
+    ) -> Result<models::RetentionPolicyTeams, ApiError> {
+        let mut query: Vec<(&str, String)> = Vec::new();
+        if let Some(v) = page {
+            query.push(("page", v.to_string()));
+        }
+        if let Some(v) = per_page {
+            query.push(("per_page", v.to_string()));
+        }
+        let query: Vec<(&str, &str)> = query.iter().map(|(a, b)| (*a, &**b)).collect();
+        self.query(
+            "GET",
+            &format!("data_retention/policies/{policy_id}/teams"),
+            Some(&query),
+            None,
+        )
+        .await
+    }
+
+    /// Apply a granular data retention policy to teams.
+    ///
+    /// Requires the "sysconsole_write_compliance_data_retention" permission.
+    pub async fn add_retention_policy_teams(
+        &self,
+        policy_id: &str,
+        team_ids: &[&str],
+    ) -> Result<models::StatusResponse, ApiError> {
+        let body = serde_json::to_string_pretty(team_ids)?;
+
+        self.query(
+            "POST",
+            &format!("data_retention/policies/{policy_id}/teams"),
+            None,
+            Some(body.as_bytes()),
+        )
+        .await
+    }
+
+    /// Stop applying a granular data retention policy to teams.
+    ///
+    /// Requires the "sysconsole_write_compliance_data_retention" permission.
+    pub async fn remove_retention_policy_teams(
+        &self,
+        policy_id: &str,
+        team_ids: &[&str],
+    ) -> Result<models::StatusResponse, ApiError> {
+        let body = serde_json::to_string_pretty(team_ids)?;
+
+        self.query(
+            "DELETE",
+            &format!("data_retention/policies/{policy_id}/teams"),
+            None,
+            Some(body.as_bytes()),
+        )
+        .await
+    }
+
+    /// Get a page of the channels a granular data retention policy applies to.
+    ///
+    /// Requires the "sysconsole_read_compliance_data_retention" permission.
+    pub async fn get_retention_policy_channels(
+        &self,
+        policy_id: &str,
+        page: Option<u64>,
+        per_page: Option<u64>,
+    ) -> Result<models::RetentionPolicyChannels, ApiError> {
+        let mut query: Vec<(&str, String)> = Vec::new();
+        if let Some(v) = page {
+            query.push(("page", v.to_string()));
+        }
+        if let Some(v) = per_page {
+            query.push(("per_page", v.to_string()));
+        }
+        let query: Vec<(&str, &str)> = query.iter().map(|(a, b)| (*a, &**b)).collect();
+        self.query(
+            "GET",
+            &format!("data_retention/policies/{policy_id}/channels"),
+            Some(&query),
+            None,
+        )
+        .await
+    }
+
+    /// Apply a granular data retention policy to channels.
+    ///
+    /// Requires the "sysconsole_write_compliance_data_retention" permission.
+    pub async fn add_retention_policy_channels(
+        &self,
+        policy_id: &str,
+        channel_ids: &[&str],
+    ) -> Result<models::StatusResponse, ApiError> {
+        let body = serde_json::to_string_pretty(channel_ids)?;
+
+        self.query(
+            "POST",
+            &format!("data_retention/policies/{policy_id}/channels"),
+            None,
+            Some(body.as_bytes()),
+        )
+        .await
+    }
+
+    /// Stop applying a granular data retention policy to channels.
+    ///
+    /// Requires the "sysconsole_write_compliance_data_retention" permission.
+    pub async fn remove_retention_policy_channels(
+        &self,
+        policy_id: &str,
+        channel_ids: &[&str],
+    ) -> Result<models::StatusResponse, ApiError> {
+        let body = serde_json::to_string_pretty(channel_ids)?;
+
+        self.query(
+            "DELETE",
+            &format!("data_retention/policies/{policy_id}/channels"),
+            None,
+            Some(body.as_bytes()),
+        )
+        .await
+    }
+
+    /// Create a compliance report.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn create_compliance_report(
+        &self,
+        report: &models::CreateComplianceReport,
+    ) -> Result<models::ComplianceReport, ApiError> {
+        let body = serde_json::to_string_pretty(report)?;
+
+        self.query("POST", "compliance/reports", None, Some(body.as_bytes()))
+            .await
+    }
+
+    /// Get a page of compliance reports.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn get_compliance_reports(
+        &self,
+        page: Option<u64>,
+        per_page: Option<u64>,
+    ) -> Result<Vec<models::ComplianceReport>, ApiError> {
+        let mut query: Vec<(&str, String)> = Vec::new();
+        if let Some(v) = page {
+            query.push(("page", v.to_string()));
+        }
+        if let Some(v) = per_page {
+            query.push(("per_page", v.to_string()));
+        }
+        let query: Vec<(&str, &str)> = query.iter().map(|(a, b)| (*a, &**b)).collect();
+        self.query("GET", "compliance/reports", Some(&query), None)
+            .await
+    }
+
+    /// Get a compliance report.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn get_compliance_report(
+        &self,
+        report_id: &str,
+    ) -> Result<models::ComplianceReport, ApiError> {
+        self.query(
+            "GET",
+            &format!("compliance/reports/{report_id}"),
+            None,
+            None,
+        )
+        .await
+    }
+
+    /// Download a finished compliance report as a zip archive.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn download_compliance_report(&self, report_id: &str) -> Result<Vec<u8>, ApiError> {
+        self.query_bytes(&format!("compliance/reports/{report_id}/download"), None)
+            .await
+    }
+
+    /// Get the message export settings from the server configuration.
+    ///
+    /// Returns `None` if the configuration has no such section.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn get_message_export_settings(
+        &self,
+    ) -> Result<Option<models::MessageExportSettings>, ApiError> {
+        self.get_config_section("MessageExportSettings").await
+    }
+
+    /// Update the message export settings in the server configuration.
+    ///
+    /// Settings that are `None` are left unchanged. Returns the updated
+    /// settings, or `None` if the configuration has no such section.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn patch_message_export_settings(
+        &self,
+        settings: &models::MessageExportSettings,
+    ) -> Result<Option<models::MessageExportSettings>, ApiError> {
+        let mut patch = serde_json::to_value(settings)?;
+        if let Value::Object(map) = &mut patch {
+            map.retain(|_, v| !v.is_null());
+        }
+        let mut config = self
+            .patch_config(&json!({ "MessageExportSettings": patch }))
+            .await?;
+        let settings = config
+            .get_mut("MessageExportSettings")
+            .map(Value::take)
+            .unwrap_or_default();
+        Ok(serde_json::from_value(settings)?)
+    }
+
+    /// Start a message export job.
+    ///
+    /// Requires the "manage_jobs" permission.
+    pub async fn create_message_export_job(&self) -> Result<models::Job, ApiError> {
+        self.create_job(&models::CreateJob {
+            job_type: models::JobType::MessageExport,
+            data: None,
+        })
+        .await
+    }
+
+    /// Start a bulk export job, optionally including file attachments.
+    ///
+    /// The resulting file can be retrieved with `download_export` once the job finishes.
+    ///
+    /// Requires the "manage_jobs" permission.
+    pub async fn create_bulk_export_job(
+        &self,
+        include_attachments: bool,
+    ) -> Result<models::Job, ApiError> {
+        self.create_job(&models::CreateJob {
+            job_type: models::JobType::ExportProcess,
+            data: Some(HashMap::from([(
+                "include_attachments".to_string(),
+                include_attachments.to_string(),
+            )])),
+        })
+        .await
+    }
+
+    /// List the names of the bulk export files on the server.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn get_exports(&self) -> Result<Vec<String>, ApiError> {
+        self.query("GET", "exports", None, None).await
+    }
+
+    /// Download a bulk export file.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn download_export(&self, export_name: &str) -> Result<Vec<u8>, ApiError> {
+        self.query_bytes(&format!("exports/{export_name}"), None)
+            .await
+    }
+
+    /// Delete a bulk export file.
+    ///
+    /// Requires the "manage_system" permission.
+    pub async fn delete_export(
+        &self,
+        export_name: &str,
+    ) -> Result<models::StatusResponse, ApiError> {
+        self.query("DELETE", &format!("exports/{export_name}"), None, None)
+            .await
+    }
+
+    /// Get the thumbnail image of a file.
+    pub async fn get_file_thumbnail(&self, file_id: &str) -> Result<Vec<u8>, ApiError> {
+        self.query_bytes(&format!("files/{file_id}/thumbnail"), None)
+            .await
+    }
+
+    /// Get the preview image of a file.
+    ///
+    /// Only available if the file's `has_preview_image` is set.
+    pub async fn get_file_preview(&self, file_id: &str) -> Result<Vec<u8>, ApiError> {
+        self.query_bytes(&format!("files/{file_id}/preview"), None)
+            .await
+    }
+
+    /// Get a public link to a file.
+    ///
+    /// Public links must be enabled on the instance.
+    pub async fn get_file_link(&self, file_id: &str) -> Result<models::FileLink, ApiError> {
+        self.query("GET", &format!("files/{file_id}/link"), None, None)
+            .await
+    }
+
+    /// Search for files in a team.
+    pub async fn search_team_files(
+        &self,
+        team_id: &str,
+        search: &models::FileSearch,
+    ) -> Result<models::FileInfoList, ApiError> {
+        let body = serde_json::to_string_pretty(search)?;
+
+        self.query(
+            "POST",
+            &format!("teams/{team_id}/files/search"),
+            None,
+            Some(body.as_bytes()),
+        )
+        .await
+    }
+
+    /// Search for files in all teams the current user is a member of.
+    pub async fn search_files(
+        &self,
+        search: &models::FileSearch,
+    ) -> Result<models::FileInfoList, ApiError> {
+        let body = serde_json::to_string_pretty(search)?;
+
+        self.query("POST", "files/search", None, Some(body.as_bytes()))
+            .await
+    }
+
+    /// Create an upload session for a file of `file_size` bytes.
+    pub async fn create_upload_session(
+        &self,
+        session: &models::CreateUploadSession,
+    ) -> Result<models::UploadSession, ApiError> {
+        let body = serde_json::to_string_pretty(session)?;
+
+        self.query("POST", "uploads", None, Some(body.as_bytes()))
+            .await
+    }
+
+    /// Get an upload session, including how many bytes the server has received.
+    pub async fn get_upload_session(
+        &self,
+        upload_id: &str,
+    ) -> Result<models::UploadSession, ApiError> {
+        self.query("GET", &format!("uploads/{upload_id}"), None, None)
+            .await
+    }
+
+    /// Get the incomplete upload sessions of a user.
+    pub async fn get_user_upload_sessions(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<models::UploadSession>, ApiError> {
+        self.query("GET", &format!("users/{user_id}/uploads"), None, None)
+            .await
+    }
+
+    /// Append data to an upload session.
+    ///
+    /// Returns the file's metadata once all of its bytes have been received,
+    /// or `None` while more data is expected.
+    pub async fn upload_data(
+        &self,
+        upload_id: &str,
+        data: Vec<u8>,
+    ) -> Result<Option<FileMetadata>, ApiError> {
+        let url = self.endpoint_url(&format!("uploads/{upload_id}"))?;
+
+        debug!("Uploading {} bytes to {}", data.len(), url);
+
+        let mut headers = self.request_headers()?;
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        let resp = self
+            .client
+            .post(url.clone())
+            .headers(headers)
+            .body(data)
+            .send()
+            .await?;
+        let resp = Self::check_response(resp, &url).await?;
+        if resp.status() == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        Ok(Some(resp.json().await?))
+    }
+
+    /// Upload the remaining data of a session from `reader`, in chunks of `chunk_size` bytes.
+    ///
+    /// `reader` must be positioned at the session's `file_offset`. After each
+    /// chunk, `on_progress` is called with the number of bytes the server has
+    /// received and the total size of the file. If the server already has all
+    /// of the data, the upload is completed without reading from `reader`.
+    pub async fn upload_from_reader<R, F>(
+        &self,
+        session: &models::UploadSession,
+        mut reader: R,
+        chunk_size: usize,
+        mut on_progress: F,
+    ) -> Result<FileMetadata, ApiError>
+    where
+        R: AsyncRead + Unpin,
+        F: FnMut(u64, u64),
+    {
+        if chunk_size == 0 {
+            return Err(ApiError::InvalidChunkSize);
+        }
+        let mut uploaded = session.file_offset;
+        loop {
+            let mut chunk = Vec::with_capacity(chunk_size);
+            if uploaded < session.file_size {
+                // the reader may go on past the end of the file
+                let remaining = session.file_size - uploaded;
+                (&mut reader)
+                    .take((chunk_size as u64).min(remaining))
+                    .read_to_end(&mut chunk)
+                    .await?;
+                if chunk.is_empty() {
+                    return Err(ApiError::IncompleteUpload {
+                        uploaded,
+                        expected: session.file_size,
+                    });
+                }
+            }
+            // an empty chunk makes the server complete a session having all of its data
+            let completing = chunk.is_empty();
+            uploaded += chunk.len() as u64;
+            let result = self.upload_data(&session.id, chunk).await?;
+            on_progress(uploaded, session.file_size);
+            match result {
+                Some(metadata) => return Ok(metadata),
+                None if completing => {
+                    return Err(ApiError::IncompleteUpload {
+                        uploaded,
+                        expected: session.file_size,
+                    })
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Resume an interrupted upload session from `reader`.
+    ///
+    /// `reader` is seeked past the bytes the server already has before
+    /// uploading the rest, as in `upload_from_reader`.
+    pub async fn resume_upload<R, F>(
+        &self,
+        upload_id: &str,
+        mut reader: R,
+        chunk_size: usize,
+        on_progress: F,
+    ) -> Result<FileMetadata, ApiError>
+    where
+        R: AsyncRead + AsyncSeek + Unpin,
+        F: FnMut(u64, u64),
+    {
+        let session = self.get_upload_session(upload_id).await?;
+        debug!(
+            "Resuming upload {upload_id} at {} of {} bytes",
+            session.file_offset, session.file_size
+        );
+        reader.seek(SeekFrom::Start(session.file_offset)).await?;
+        self.upload_from_reader(&session, reader, chunk_size, on_progress)
+            .await
+    }
+}
+
+#[cfg(has_runtime)]
+/// Read messages from a new websocket connection until the server accepts its authentication.
+///
+/// The server either replies to the authentication challenge sent with `challenge_seq`,
+/// or sends a `Hello` event. Returns the messages read, for the connection to handle.
+async fn wait_for_authentication(
+    stream: &mut WebSocketStream<ConnectStream>,
+    challenge_seq: u64,
+) -> Result<Vec<Message>, ApiError> {
+    let mut received = Vec::new();
+    while let Some(message) = stream.next().await {
+        match message.map_err(Box::new)? {
+            Message::Text(text) => {
+                let value: Value = serde_json::from_str(&text).unwrap_or_default();
+                if value["seq_reply"].as_u64() == Some(challenge_seq) {
+                    if value["status"] == "OK" {
+                        debug!("Websocket authentication accepted");
+                        return Ok(received);
+                    }
+                    let error = value["error"]["message"]
+                        .as_str()
+                        .map(String::from)
+                        .unwrap_or_else(|| value["error"].to_string());
+                    return Err(ApiError::WebsocketAuthenticationFailed(error));
+                }
+                let hello = value["event"] == "hello";
+                received.push(Message::Text(text));
+                if hello {
+                    return Ok(received);
+                }
+            }
+            Message::Close(frame) => {
+                let reason = frame
+                    .map(|frame| frame.reason.to_string())
+                    .filter(|reason| !reason.is_empty())
+                    .unwrap_or_else(|| "the connection was closed".to_string());
+                return Err(ApiError::WebsocketAuthenticationFailed(reason));
+            }
+            _ => {}
+        }
+    }
+    Err(ApiError::WebsocketAuthenticationFailed(
+        "the connection was closed".to_string(),
+    ))
+}
+
+/// Extract the file name from a `Content-Disposition` header value.
+fn content_disposition_filename(value: &str) -> Option<String> {
+    value.split(';').find_map(|param| {
+        let (key, name) = param.trim().split_once('=')?;
+        (key.eq_ignore_ascii_case("filename")).then(|| name.trim_matches('"').to_string())
+    })
+}
+
+#[cfg(test)]
+mod url_tests {
+    use super::{AuthenticationData, Mattermost};
+    use crate::errors::ApiError;
+
+    impl PartialEq for ApiError {
+        fn eq(&self, other: &Self) -> bool {
+            self.to_string() == other.to_string()
+        }
+    }
+
+    #[test]
+    fn invalid_instance_url_fails_fast() {
+        let Err(err) = Mattermost::new("herp derp", AuthenticationData::from_access_token("x"))
+        else {
+            panic!("Expected an error")
+        };
+
+        assert_eq!(err, ApiError::UrlError(url::ParseError::EmptyHost))
+    }
+
+    #[test]
+    fn api_v4_path_is_added_by_default() {
+        let client = Mattermost::new(
+            "http://www.mattermost.com",
+            AuthenticationData::from_access_token("x"),
+        )
+        .expect("This should work");
+
+        assert_eq!(
+            client.instance_url.as_str(),
+            "http://www.mattermost.com/api/v4/"
+        )
+    }
+
+    #[test]
+    fn api_path_can_be_overridden() {
+        let client = Mattermost::new(
+            "http://www.mattermost.com/ipa/v5/",
+            AuthenticationData::from_access_token("x"),
+        )
+        .expect("This should work");
+
+        assert_eq!(
+            client.instance_url.as_str(),
+            "http://www.mattermost.com/ipa/v5/"
+        )
+    }
+
+    #[cfg(has_runtime)]
+    #[test]
+    fn http_urls_are_properly_converted_to_ws_urls() {
+        let http_client = Mattermost::new(
+            "http://www.mattermost.com",
+            AuthenticationData::from_access_token("x"),
+        )
+        .unwrap();
+        assert_eq!(
+            http_client.ws_instance_url().unwrap().as_str(),
+            "ws://www.mattermost.com/api/v4/"
+        );
+
+        let https_client = Mattermost::new(
+            "https://www.mattermost.com/",
+            AuthenticationData::from_access_token("x"),
+        )
+        .unwrap();
+        assert_eq!(
+            https_client.ws_instance_url().unwrap().as_str(),
+            "wss://www.mattermost.com/api/v4/"
+        );
+    }
+
+    #[test]
+    fn endpoint_urls_are_joined_as_expected() {
+        let client = Mattermost::new(
+            "https://www.mattermost.com",
+            AuthenticationData::from_access_token("x"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            client.endpoint_url("/herp/derp").unwrap().as_str(),
+            "https://www.mattermost.com/api/v4/herp/derp",
+        );
+    }
+}
+
+#[cfg(test)]
+mod group_tests {
+    use super::{AuthenticationData, Mattermost};
+    use crate::models::GroupSyncableType;
+    use mockito::{mock, Matcher};
+    use serde_json::json;
+
+    const GROUP: &str = r#"{"id":"g1","name":"devs","display_name":"Developers",
+        "description":"","source":"custom","remote_id":null,"create_at":1,"update_at":1,
+        "delete_at":0,"member_count":2,"allow_reference":true}"#;
+
+    fn client() -> Mattermost {
+        Mattermost::new(
+            mockito::server_url(),
+            AuthenticationData::from_access_token("x"),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn groups_are_searched() {
+        let _m = mock("GET", "/api/v4/groups")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("q".into(), "dev".into()),
+                Matcher::UrlEncoded("page".into(), "0".into()),
+                Matcher::UrlEncoded("per_page".into(), "20".into()),
+                Matcher::UrlEncoded("include_member_count".into(), "true".into()),
+            ]))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(format!("[{GROUP}]"))
+            .create();
+
+        let groups = client()
+            .get_groups(Some("dev"), Some(0), Some(20), Some(true))
+            .await
+            .unwrap();
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].name.as_deref(), Some("devs"));
+        assert_eq!(groups[0].member_count, Some(2));
+    }
+
+    #[tokio::test]
+    async fn members_are_added_and_removed() {
+        let member = r#"[{"group_id":"g1","user_id":"u1","create_at":1,"delete_at":0}]"#;
+        let added = mock("POST", "/api/v4/groups/g1/members")
+            .match_body(Matcher::Json(json!({ "user_ids": ["u1"] })))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(member)
+            .expect(1)
+            .create();
+        let removed = mock("DELETE", "/api/v4/groups/g1/members")
+            .match_body(Matcher::Json(json!({ "user_ids": ["u1"] })))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(member)
+            .expect(1)
+            .create();
+        let client = client();
+
+        let members = client
+            .add_group_members("g1", vec!["u1".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(members[0].user_id, "u1");
+        client
+            .delete_group_members("g1", vec!["u1".to_string()])
+            .await
+            .unwrap();
+
+        added.assert();
+        removed.assert();
+    }
+
+    #[tokio::test]
+    async fn syncables_are_linked_and_unlinked() {
+        let linked = mock("POST", "/api/v4/groups/g1/channels/c1/link")
+            .match_body(Matcher::Json(json!({ "auto_add": true })))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"group_id":"g1","channel_id":"c1","auto_add":true,
+                "create_at":1,"update_at":1,"delete_at":0}"#,
+            )
+            .expect(1)
+            .create();
+        let unlinked = mock("DELETE", "/api/v4/groups/g1/teams/t1/link")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"status":"OK"}"#)
+            .expect(1)
+            .create();
+        let client = client();
+
+        let syncable = client
+            .link_group_syncable("g1", GroupSyncableType::Channel, "c1", true)
+            .await
+            .unwrap();
+        assert_eq!(syncable.channel_id.as_deref(), Some("c1"));
+        assert!(syncable.auto_add);
+        let status = client
+            .unlink_group_syncable("g1", GroupSyncableType::Team, "t1")
+            .await
+            .unwrap();
+        assert_eq!(status.status, "OK");
+
+        linked.assert();
+        unlinked.assert();
+    }
+}
+
+#[cfg(test)]
+mod plugin_tests {
+    use super::{AuthenticationData, Mattermost};
+    use crate::models::PluginState;
+    use mockito::{mock, Matcher};
+
+    const MANIFEST: &str = r#"{"id":"com.example.plugin","name":"Example","version":"1.0.0"}"#;
+
+    fn client() -> Mattermost {
+        Mattermost::new(
+            mockito::server_url(),
+            AuthenticationData::from_access_token("x"),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn plugins_are_uploaded_as_multipart() {
+        let _m = mock("POST", "/api/v4/plugins")
+            .match_header(
+                "content-type",
+                Matcher::Regex("^multipart/form-data; boundary=".to_string()),
+            )
+            .match_body(Matcher::Regex(
+                r#"(?s)name="plugin"; filename="plugin.tar.gz"\r\n.*\r\nbundle\r\n.*name="force"\r\n\r\ntrue\r\n"#
+                    .to_string(),
+            ))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(MANIFEST)
+            .create();
+
+        let manifest = client()
+            .upload_plugin(b"bundle".to_vec(), true)
+            .await
+            .unwrap();
+
+        assert_eq!(manifest.id, "com.example.plugin");
+    }
+
+    #[tokio::test]
+    async fn plugins_are_installed_from_urls() {
+        let _m = mock("POST", "/api/v4/plugins/install_from_url")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded(
+                    "plugin_download_url".into(),
+                    "https://example.com/plugin.tar.gz?v=1".into(),
+                ),
+                Matcher::UrlEncoded("force".into(), "false".into()),
+            ]))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(MANIFEST)
+            .create();
+
+        let manifest = client()
+            .install_plugin_from_url("https://example.com/plugin.tar.gz?v=1", false)
+            .await
+            .unwrap();
+
+        assert_eq!(manifest.version, "1.0.0");
+    }
+
+    #[tokio::test]
+    async fn plugins_are_enabled_and_disabled() {
+        let enabled = mock("POST", "/api/v4/plugins/com.example.plugin/enable")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"status":"OK"}"#)
+            .expect(1)
+            .create();
+        let disabled = mock("POST", "/api/v4/plugins/com.example.plugin/disable")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"status":"OK"}"#)
+            .expect(1)
+            .create();
+        let client = client();
+
+        client.enable_plugin("com.example.plugin").await.unwrap();
+        client.disable_plugin("com.example.plugin").await.unwrap();
+
+        enabled.assert();
+        disabled.assert();
+    }
+
+    #[tokio::test]
+    async fn plugin_states_are_mapped_from_numbers() {
+        let status = |state: i64| {
+            format!(
+                r#"{{"plugin_id":"p","cluster_id":"c","plugin_path":"","state":{state},
+                "error":null,"name":"","description":"","version":"1.0.0"}}"#
+            )
+        };
+        let body = format!("[{}]", (0..=6).map(status).collect::<Vec<_>>().join(","));
+        let _m = mock("GET", "/api/v4/plugins/statuses")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(body)
+            .create();
+
+        let statuses = client().get_plugin_statuses().await.unwrap();
+
+        assert_eq!(
+            statuses
+                .iter()
+                .map(|status| status.state)
+                .collect::<Vec<_>>(),
+            [
+                PluginState::NotRunning,
+                PluginState::Starting,
+                PluginState::Running,
+                PluginState::FailedToStart,
+                PluginState::FailedToStayRunning,
+                PluginState::Stopping,
+                PluginState::Other(6),
+            ]
+        );
+        assert_eq!(i64::from(PluginState::Stopping), 5);
+    }
+}
+
+#[cfg(all(test, has_runtime))]
+mod job_tests {
+    use super::{AuthenticationData, Mattermost};
+    use crate::models::JobStatus;
+    use mockito::mock;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn wait_for_job_returns_once_terminal() {
+        let _m = mock("GET", "/api/v4/jobs/job1")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"id":"job1","type":"ldap_sync","create_at":1,"start_at":2,
+                "last_activity_at":3,"status":"success","progress":100,"data":null}"#,
+            )
+            .create();
+        let client = Mattermost::new(
+            mockito::server_url(),
+            AuthenticationData::from_access_token("x"),
+        )
+        .unwrap();
+
+        let job = client
+            .wait_for_job("job1", Duration::from_millis(10), Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        assert_eq!(job.status, JobStatus::Success);
+    }
+}
+
+#[cfg(test)]
+mod system_tests {
+    use super::{AuthenticationData, Mattermost};
+    use mockito::{mock, Matcher};
+
+    #[tokio::test]
+    async fn ping_reports_the_server_status() {
+        let _m = mock("GET", "/api/v4/system/ping")
+            .match_query(Matcher::UrlEncoded(
+                "get_server_status".into(),
+                "true".into(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"ActiveSearchBackend":"database","AndroidLatestVersion":"",
+                "AndroidMinVersion":"","CanReceiveNotifications":"true",
+                "IosLatestVersion":"","IosMinVersion":"","database_status":"OK",
+                "filestore_status":"OK","status":"OK"}"#,
+            )
+            .create();
+        let client = Mattermost::new(
+            mockito::server_url(),
+            AuthenticationData::from_access_token("x"),
+        )
+        .unwrap();
+
+        let status = client.ping(true).await.unwrap();
+
+        assert_eq!(status.status, "OK");
+        assert_eq!(status.database_status.as_deref(), Some("OK"));
+        assert_eq!(status.filestore_status.as_deref(), Some("OK"));
+        assert_eq!(status.active_search_backend.as_deref(), Some("database"));
+        assert!(status.other.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod compliance_tests {
+    use super::{AuthenticationData, Mattermost};
+    use crate::models::MessageExportSettings;
+    use mockito::{mock, Matcher};
+
+    fn client() -> Mattermost {
+        Mattermost::new(
+            mockito::server_url(),
+            AuthenticationData::from_access_token("x"),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn retention_policy_teams_are_paged() {
+        let _m = mock("GET", "/api/v4/data_retention/policies/policy1/teams")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("page".into(), "1".into()),
+                Matcher::UrlEncoded("per_page".into(), "10".into()),
+            ]))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"teams":[{"id":"team1","create_at":1,"update_at":1,"delete_at":0,
+                "display_name":"Team","name":"team","description":"","email":"",
+                "type":"O","allowed_domains":"","invite_id":"","allow_open_invite":false,
+                "policy_id":"policy1"}],"total_count":11}"#,
+            )
+            .create();
+
+        let teams = client()
+            .get_retention_policy_teams("policy1", Some(1), Some(10))
+            .await
+            .unwrap();
+
+        assert_eq!(teams.total_count, 11);
+        assert_eq!(teams.teams[0].id, "team1");
+    }
+
+    #[tokio::test]
+    async fn config_sections_are_read_typed() {
+        let _m = mock("GET", "/api/v4/config")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"ServiceSettings":{"SiteURL":"https://chat.example.com",
+                "EnableUserAccessTokens":true,"ListenAddress":":8065"},
+                "FileSettings":{"EnablePublicLink":false,"MaxFileSize":52428800}}"#,
+            )
+            .create();
+        let client = client();
+
+        let service = client.get_service_settings().await.unwrap().unwrap();
+        let files = client.get_file_settings().await.unwrap().unwrap();
+        let export = client.get_message_export_settings().await.unwrap();
+
+        assert_eq!(
+            service.site_url.as_deref(),
+            Some("https://chat.example.com")
+        );
+        assert_eq!(service.enable_user_access_tokens, Some(true));
+        assert_eq!(service.websocket_url, None);
+        assert_eq!(service.other["ListenAddress"], ":8065");
+        assert_eq!(files.enable_public_link, Some(false));
+        assert_eq!(files.max_file_size, Some(52428800));
+        assert!(export.is_none());
+    }
+
+    #[tokio::test]
+    async fn patched_message_export_settings_can_be_missing() {
+        let _m = mock("PUT", "/api/v4/config/patch")
+            .match_body(Matcher::PartialJson(
+                serde_json::json!({ "MessageExportSettings": { "EnableExport": true } }),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"ServiceSettings":{}}"#)
+            .create();
+        let settings = MessageExportSettings {
+            enable_export: Some(true),
+            ..Default::default()
+        };
+
+        let patched = client()
+            .patch_message_export_settings(&settings)
+            .await
+            .unwrap();
+
+        assert!(patched.is_none());
+    }
+}
+
+#[cfg(test)]
+mod file_tests {
+    use super::{AuthenticationData, Mattermost};
+    use crate::errors::ApiError;
+    use crate::models::{FileSearch, UploadFile};
+    use futures_util::io::Cursor;
+    use mockito::{mock, Matcher};
+
+    #[tokio::test]
+    async fn upload_files_sends_client_ids_before_each_file() {
+        let _m = mock("POST", "/api/v4/files")
+            .match_header(
+                "content-type",
+                Matcher::Regex("^multipart/form-data; boundary=".to_string()),
+            )
+            .match_body(Matcher::Regex(
+                r#"(?s)name="channel_id"\r\n\r\nchan1\r\n.*name="client_ids"\r\n\r\nreport\r\n.*filename="report.csv"\r\nContent-Type: text/csv\r\n\r\na,b\r\n.*name="client_ids"\r\n\r\nchart\r\n.*filename="chart.png""#
+                    .to_string(),
+            ))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"file_infos":[],"client_ids":["report","chart"]}"#)
+            .create();
         let client = Mattermost::new(
-            "https://www.mattermost.com",
+            mockito::server_url(),
+            AuthenticationData::from_access_token("x"),
+        )
+        .unwrap();
+
+        let resp = client
+            .upload_files(
+                "chan1",
+                vec![
+                    UploadFile::new("report.csv", b"a,b".to_vec())
+                        .with_mime_type("text/csv")
+                        .with_client_id("report"),
+                    UploadFile::new("chart.png", vec![0, 1, 2]).with_client_id("chart"),
+                ],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(resp.client_ids, vec!["report", "chart"]);
+    }
+
+    #[tokio::test]
+    async fn public_file_links_are_fetched() {
+        let _m = mock("GET", "/api/v4/files/f1/link")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"link":"https://mattermost.example.com/files/f1/public?h=abc"}"#)
+            .create();
+        let client = Mattermost::new(
+            mockito::server_url(),
             AuthenticationData::from_access_token("x"),
         )
         .unwrap();
 
+        let link = client.get_file_link("f1").await.unwrap();
+
         assert_eq!(
-            client.endpoint_url("/herp/derp").unwrap().as_str(),
-            "https://www.mattermost.com/api/v4/herp/derp",
+            link.link,
+            "https://mattermost.example.com/files/f1/public?h=abc"
+        );
+    }
+
+    #[tokio::test]
+    async fn files_are_searched_in_teams_and_channels() {
+        let found = r#"{"order":["f2","f1"],"file_infos":{
+            "f1":{"id":"f1","user_id":"u1","post_id":"p1","create_at":1,"update_at":1,
+            "delete_at":0,"name":"report-old.pdf","extension":"pdf","size":1,"mime_type":"application/pdf"},
+            "f2":{"id":"f2","user_id":"u1","post_id":"p2","create_at":2,"update_at":2,
+            "delete_at":0,"name":"report.pdf","extension":"pdf","size":1,"mime_type":"application/pdf"}},
+            "next_file_id":"","prev_file_id":""}"#;
+        let team = mock("POST", "/api/v4/teams/t1/files/search")
+            .match_body(Matcher::PartialJson(serde_json::json!({
+                "terms": "report in:town-square",
+                "is_or_search": false,
+                "page": 0,
+            })))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(found)
+            .expect(1)
+            .create();
+        let all = mock("POST", "/api/v4/files/search")
+            .match_body(Matcher::PartialJson(
+                serde_json::json!({ "terms": "in:town-square" }),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(found)
+            .expect(1)
+            .create();
+        let client = Mattermost::new(
+            mockito::server_url(),
+            AuthenticationData::from_access_token("x"),
+        )
+        .unwrap();
+        let search = FileSearch {
+            page: Some(0),
+            ..FileSearch::new("report").in_channel("town-square")
+        };
+
+        let files = client.search_team_files("t1", &search).await.unwrap();
+        // in order of relevance
+        assert_eq!(
+            files.files().map(|file| &file.name[..]).collect::<Vec<_>>(),
+            ["report.pdf", "report-old.pdf"]
+        );
+        client
+            .search_files(&FileSearch::new("").in_channel("town-square"))
+            .await
+            .unwrap();
+
+        team.assert();
+        all.assert();
+    }
+
+    #[tokio::test]
+    async fn resume_upload_skips_received_bytes() {
+        let _session = mock("GET", "/api/v4/uploads/up1")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"id":"up1","type":"attachment","create_at":1,"user_id":"u1",
+                "channel_id":"c1","filename":"a.txt","file_size":6,"file_offset":3}"#,
+            )
+            .create();
+        let _data = mock("POST", "/api/v4/uploads/up1")
+            .match_body("def")
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"id":"f1","user_id":"u1","post_id":"","create_at":1,"update_at":1,
+                "delete_at":0,"name":"a.txt","extension":"txt","size":6,"mime_type":"text/plain"}"#,
+            )
+            .create();
+        let client = Mattermost::new(
+            mockito::server_url(),
+            AuthenticationData::from_access_token("x"),
+        )
+        .unwrap();
+
+        let mut progress = Vec::new();
+        let metadata = client
+            .resume_upload(
+                "up1",
+                Cursor::new(b"abcdef".to_vec()),
+                1024,
+                |done, total| progress.push((done, total)),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(metadata.id, "f1");
+        assert_eq!(progress, vec![(6, 6)]);
+    }
+
+    #[tokio::test]
+    async fn uploads_stop_at_the_session_size() {
+        let _first = mock("POST", "/api/v4/uploads/up3")
+            .match_body("abc")
+            .with_status(204)
+            .expect(1)
+            .create();
+        let _last = mock("POST", "/api/v4/uploads/up3")
+            .match_body("d")
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"id":"f3","user_id":"u1","post_id":"","create_at":1,"update_at":1,
+                "delete_at":0,"name":"a.txt","extension":"txt","size":4,"mime_type":"text/plain"}"#,
+            )
+            .expect(1)
+            .create();
+        let client = Mattermost::new(
+            mockito::server_url(),
+            AuthenticationData::from_access_token("x"),
+        )
+        .unwrap();
+        let session = serde_json::from_str(
+            r#"{"id":"up3","type":"attachment","create_at":1,"user_id":"u1",
+            "channel_id":"c1","filename":"a.txt","file_size":4,"file_offset":0}"#,
+        )
+        .unwrap();
+
+        let mut progress = Vec::new();
+        let metadata = client
+            .upload_from_reader(
+                &session,
+                Cursor::new(b"abcdefgh".to_vec()),
+                3,
+                |done, total| progress.push((done, total)),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(metadata.id, "f3");
+        assert_eq!(progress, vec![(3, 4), (4, 4)]);
+        _first.assert();
+        _last.assert();
+    }
+
+    #[tokio::test]
+    async fn complete_sessions_are_finished_without_data() {
+        let _data = mock("POST", "/api/v4/uploads/up2")
+            .match_body("")
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"id":"f2","user_id":"u1","post_id":"","create_at":1,"update_at":1,
+                "delete_at":0,"name":"a.txt","extension":"txt","size":6,"mime_type":"text/plain"}"#,
+            )
+            .expect(1)
+            .create();
+        let client = Mattermost::new(
+            mockito::server_url(),
+            AuthenticationData::from_access_token("x"),
+        )
+        .unwrap();
+        let session = serde_json::from_str(
+            r#"{"id":"up2","type":"attachment","create_at":1,"user_id":"u1",
+            "channel_id":"c1","filename":"a.txt","file_size":6,"file_offset":6}"#,
+        )
+        .unwrap();
+
+        let metadata = client
+            .upload_from_reader(&session, Cursor::new(Vec::new()), 1024, |_, _| {})
+            .await
+            .unwrap();
+
+        assert_eq!(metadata.id, "f2");
+        _data.assert();
+        assert!(matches!(
+            client
+                .upload_from_reader(&session, Cursor::new(Vec::new()), 0, |_, _| {})
+                .await,
+            Err(ApiError::InvalidChunkSize)
+        ));
+    }
+}
+
+#[cfg(test)]
+mod download_tests {
+    use super::content_disposition_filename;
+
+    #[test]
+    fn filename_is_read_from_content_disposition() {
+        assert_eq!(
+            content_disposition_filename(
+                r#"attachment;filename="report 1.csv"; filename*=UTF-8''report%201.csv"#
+            ),
+            Some("report 1.csv".to_string())
+        );
+        assert_eq!(content_disposition_filename("inline"), None);
+    }
+}
+
+#[cfg(all(test, has_runtime))]
+mod websocket_tests {
+    use super::{AuthenticationData, Mattermost};
+    use crate::errors::ApiError;
+    use crate::filter::EventFilter;
+    use crate::reconnect::{BackoffPolicy, ConnectionState};
+    use crate::runtime;
+    use crate::socket::WebsocketEventType;
+    use crate::socket::{
+        ConnectionInfo, DisconnectReason, MissedEvents, WebsocketEvent, WebsocketHandler,
+        WebsocketSender,
+    };
+    use crate::transport::TransportConfig;
+    use async_trait::async_trait;
+    use async_tungstenite::tungstenite::{handshake::server::Request, Message};
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use std::{
+        collections::HashMap,
+        future::Future,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    // runtime-agnostic, so the tests run against the connector of every runtime
+    use async_std::net::{TcpListener, TcpStream};
+    use async_tungstenite::tungstenite;
+    use futures_util::{AsyncReadExt, AsyncWriteExt};
+    use reqwest::header::{HeaderName, HeaderValue};
+
+    /// Handler recording what it was called with.
+    #[derive(Default)]
+    struct Recorder {
+        calls: Mutex<Vec<String>>,
+        /// How long handling an event takes
+        delay: Duration,
+    }
+
+    #[async_trait]
+    impl WebsocketHandler for Recorder {
+        async fn callback(&self, message: WebsocketEvent) {
+            runtime::sleep(self.delay).await;
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("event {}", message.seq));
+        }
+
+        async fn on_missed_events(&self, missed: MissedEvents) {
+            self.calls.lock().unwrap().push(format!(
+                "missed {} -> {}",
+                missed.previous_connection_id, missed.connection_id
+            ));
+        }
+
+        async fn on_connect(&self, info: ConnectionInfo) {
+            self.calls.lock().unwrap().push(format!(
+                "connect {} resumed={}",
+                info.connection_id, info.resumed
+            ));
+        }
+
+        async fn on_disconnect(&self, reason: DisconnectReason) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("disconnect {reason:?}"));
+        }
+
+        async fn on_error(&self, error: &ApiError) {
+            self.calls.lock().unwrap().push(format!("error {error}"));
+        }
+
+        async fn on_parse_error(&self, text: &str) {
+            self.calls.lock().unwrap().push(format!("unparsed {text}"));
+        }
+    }
+
+    fn status_change(seq: usize) -> Message {
+        Message::Text(format!(
+            r#"{{"event":"status_change","data":{{"status":"online","user_id":"u1"}},
+            "broadcast":{{"omit_users":null,"user_id":"u1","channel_id":"","team_id":""}},"seq":{seq}}}"#
+        ))
+    }
+
+    /// Wait for the next text message, skipping keep-alive pings.
+    async fn next_text(ws: &mut ServerStream) -> String {
+        loop {
+            match ws.next().await {
+                Some(Ok(Message::Text(text))) => return text,
+                Some(Ok(_)) => {}
+                other => panic!("Expected a text message, got {other:?}"),
+            }
+        }
+    }
+
+    fn hello(connection_id: &str) -> Message {
+        Message::Text(HELLO.replace("conn1", connection_id))
+    }
+
+    const HELLO: &str = r#"{"event":"hello","data":{"connection_id":"conn1","server_version":"9.0"},
+        "broadcast":{"omit_users":null,"user_id":"u1","channel_id":"","team_id":""},"seq":0}"#;
+
+    type ServerStream = async_tungstenite::WebSocketStream<TcpStream>;
+
+    /// Start a server running `script` for each websocket connection, returning the client to connect with.
+    ///
+    /// The script is given the index of the connection and the upgrade request it was opened with.
+    async fn serve<F, Fut>(script: F) -> Mattermost
+    where
+        F: Fn(usize, Request, ServerStream) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for index in 0.. {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let mut request = Request::default();
+                // the callback signature is set by tungstenite
+                #[allow(clippy::result_large_err)]
+                let ws = async_tungstenite::accept_hdr_async(tcp, |req: &Request, resp| {
+                    *request.uri_mut() = req.uri().clone();
+                    *request.headers_mut() = req.headers().clone();
+                    Ok(resp)
+                })
+                .await
+                .unwrap();
+                script(index, request, ws).await;
+            }
+        });
+        Mattermost::new(
+            format!("http://{addr}"),
+            AuthenticationData::from_access_token("token"),
+        )
+        .unwrap()
+    }
+
+    /// Handler getting the user statuses when connected, then closing the connection.
+    struct StatusReader {
+        sender: WebsocketSender,
+        statuses: Mutex<Option<Result<HashMap<String, String>, ApiError>>>,
+    }
+
+    #[async_trait]
+    impl WebsocketHandler for StatusReader {
+        async fn callback(&self, message: WebsocketEvent) {
+            assert!(matches!(message.event, WebsocketEventType::Hello { .. }));
+            *self.statuses.lock().unwrap() = Some(self.sender.get_statuses().await);
+            self.sender.close().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn events_and_action_replies_are_handled() {
+        let mut api = serve(|_, _, mut ws| async move {
+            let auth: Value = serde_json::from_str(&next_text(&mut ws).await).unwrap();
+            assert_eq!(auth["data"]["token"], "token");
+            ws.send(Message::Text(HELLO.to_string())).await.unwrap();
+
+            let action: Value = serde_json::from_str(&next_text(&mut ws).await).unwrap();
+            assert_eq!(action["action"], "get_statuses");
+            let reply =
+                json!({ "status": "OK", "seq_reply": action["seq"], "data": { "u1": "dnd" } });
+            ws.send(Message::Text(reply.to_string())).await.unwrap();
+
+            // run until the client has closed the connection
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await;
+        let handler = StatusReader {
+            sender: api
+                .websocket_sender()
+                .with_reply_timeout(Duration::from_secs(2)),
+            statuses: Mutex::default(),
+        };
+
+        api.connect_to_websocket(&handler).await.unwrap();
+
+        let statuses = handler.statuses.lock().unwrap().take().unwrap();
+        assert_eq!(statuses.unwrap()["u1"], "dnd");
+        assert!(!handler.sender.is_connected());
+    }
+
+    #[tokio::test]
+    async fn events_are_streamed() {
+        let api = serve(|_, _, mut ws| async move {
+            next_text(&mut ws).await;
+            ws.send(Message::Text(HELLO.to_string())).await.unwrap();
+            let action: Value = serde_json::from_str(&next_text(&mut ws).await).unwrap();
+            let reply = json!({ "status": "OK", "seq_reply": action["seq"], "data": {} });
+            ws.send(Message::Text(reply.to_string())).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await;
+
+        let (mut events, sender) = api.connect_events().await.unwrap();
+        let hello = events.next().await.unwrap().unwrap();
+        assert!(matches!(hello.event, WebsocketEventType::Hello { .. }));
+        // replies are received without polling the stream
+        assert!(sender.get_statuses().await.unwrap().is_empty());
+
+        sender.close().unwrap();
+        assert!(events.next().await.is_none());
+        assert!(!sender.is_connected());
+    }
+
+    /// Handler showing the user as typing for every event.
+    struct Typist {
+        sender: WebsocketSender,
+        results: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl WebsocketHandler for Typist {
+        async fn callback(&self, message: WebsocketEvent) {
+            let result = self.sender.user_typing("c1", None).await;
+            self.results
+                .lock()
+                .unwrap()
+                .push(format!("event {} {result:?}", message.seq));
+        }
+    }
+
+    #[tokio::test]
+    async fn actions_can_be_sent_from_callbacks() {
+        let mut api = serve(|_, _, mut ws| async move {
+            next_text(&mut ws).await;
+            ws.send(hello("conn1")).await.unwrap();
+            ws.send(status_change(1)).await.unwrap();
+            for _ in 0..2 {
+                let action: Value = serde_json::from_str(&next_text(&mut ws).await).unwrap();
+                assert_eq!(action["action"], "user_typing");
+                assert_eq!(action["data"]["channel_id"], "c1");
+                let reply = json!({ "status": "OK", "seq_reply": action["seq"] });
+                ws.send(Message::Text(reply.to_string())).await.unwrap();
+            }
+            ws.close(None).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await;
+        let handler = Typist {
+            sender: api
+                .websocket_sender()
+                .with_reply_timeout(Duration::from_secs(2)),
+            results: Mutex::default(),
+        };
+
+        api.connect_to_websocket(&handler).await.unwrap();
+
+        assert_eq!(
+            *handler.results.lock().unwrap(),
+            vec!["event 0 Ok(())", "event 1 Ok(())"]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnecting_resumes_and_reports_unrecoverable_gaps() {
+        let mut api = serve(|index, request, mut ws| async move {
+            let uri = request.uri().to_string();
+            next_text(&mut ws).await;
+            if index == 0 {
+                assert_eq!(uri, "/api/v4/websocket");
+                ws.send(hello("conn1")).await.unwrap();
+                ws.send(status_change(1)).await.unwrap();
+                ws.send(status_change(3)).await.unwrap();
+            } else {
+                assert_eq!(
+                    uri,
+                    "/api/v4/websocket?connection_id=conn1&sequence_number=2"
+                );
+                ws.send(hello("conn2")).await.unwrap();
+                ws.close(None).await.unwrap();
+            }
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await;
+        let handler = Recorder::default();
+
+        let err = api.connect_to_websocket(&handler).await.unwrap_err();
+        assert_eq!(
+            err,
+            ApiError::WebsocketSequenceGap {
+                expected: 2,
+                received: 3
+            }
+        );
+        api.connect_to_websocket(&handler).await.unwrap();
+
+        assert_eq!(
+            *handler.calls.lock().unwrap(),
+            vec![
+                "connect conn1 resumed=false",
+                "event 0",
+                "event 1",
+                "error Websocket event 2 was missed, got event 3",
+                r#"disconnect Error("Websocket event 2 was missed, got event 3")"#,
+                "connect conn2 resumed=false",
+                "missed conn1 -> conn2",
+                "event 0",
+                r#"disconnect Closed { code: None, reason: "" }"#,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn resumed_connections_continue_the_sequence() {
+        let mut api = serve(|index, request, mut ws| async move {
+            let auth: Value = serde_json::from_str(&next_text(&mut ws).await).unwrap();
+            if index == 0 {
+                ws.send(hello("conn1")).await.unwrap();
+                ws.send(status_change(1)).await.unwrap();
+            } else {
+                assert_eq!(
+                    request.uri().to_string(),
+                    "/api/v4/websocket?connection_id=conn1&sequence_number=2"
+                );
+                // no hello is sent when resuming
+                let reply = json!({ "status": "OK", "seq_reply": auth["seq"] });
+                ws.send(Message::Text(reply.to_string())).await.unwrap();
+                ws.send(status_change(2)).await.unwrap();
+                ws.send(status_change(3)).await.unwrap();
+            }
+            ws.close(None).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await;
+        let handler = Recorder::default();
+
+        api.connect_to_websocket(&handler).await.unwrap();
+        api.connect_to_websocket(&handler).await.unwrap();
+
+        assert_eq!(
+            *handler.calls.lock().unwrap(),
+            vec![
+                "connect conn1 resumed=false",
+                "event 0",
+                "event 1",
+                r#"disconnect Closed { code: None, reason: "" }"#,
+                "connect conn1 resumed=true",
+                "event 2",
+                "event 3",
+                r#"disconnect Closed { code: None, reason: "" }"#,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unparsable_messages_are_reported_and_skipped() {
+        const BAD_POST: &str = r#"{"event":"posted","data":{},
+            "broadcast":{"omit_users":null,"user_id":"","channel_id":"c1","team_id":""},"seq":2}"#;
+        let mut api = serve(|_, _, mut ws| async move {
+            next_text(&mut ws).await;
+            ws.send(hello("conn1")).await.unwrap();
+            ws.send(Message::Text("{not json".to_string()))
+                .await
+                .unwrap();
+            let unknown = status_change(1)
+                .into_text()
+                .unwrap()
+                .replace("status_change", "some_future_event");
+            ws.send(Message::Text(unknown)).await.unwrap();
+            ws.send(Message::Text(BAD_POST.to_string())).await.unwrap();
+            ws.send(status_change(3)).await.unwrap();
+            ws.close(None).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await;
+        let handler = Recorder::default();
+
+        api.connect_to_websocket(&handler).await.unwrap();
+
+        assert_eq!(
+            *handler.calls.lock().unwrap(),
+            vec![
+                "connect conn1 resumed=false".to_string(),
+                "event 0".to_string(),
+                "unparsed {not json".to_string(),
+                "event 1".to_string(),
+                format!("unparsed {BAD_POST}"),
+                "event 3".to_string(),
+                r#"disconnect Closed { code: None, reason: "" }"#.to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn filtered_events_are_not_dispatched() {
+        let mut api = serve(|_, _, mut ws| async move {
+            next_text(&mut ws).await;
+            ws.send(hello("conn1")).await.unwrap();
+            ws.send(status_change(1)).await.unwrap();
+            let other = status_change(2)
+                .into_text()
+                .unwrap()
+                .replace("status_change", "some_future_event");
+            ws.send(Message::Text(other)).await.unwrap();
+            ws.send(status_change(3)).await.unwrap();
+            ws.close(None).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await
+        .with_event_filter(EventFilter::new().event_types(["status_change"]));
+        let handler = Recorder::default();
+
+        api.connect_to_websocket(&handler).await.unwrap();
+
+        assert_eq!(
+            *handler.calls.lock().unwrap(),
+            vec![
+                "connect conn1 resumed=false",
+                "event 1",
+                "event 3",
+                r#"disconnect Closed { code: None, reason: "" }"#,
+            ]
+        );
+    }
+
+    #[cfg(feature = "ws-keep-alive")]
+    #[tokio::test]
+    async fn silent_servers_end_the_connection() {
+        let mut api = serve(|_, _, mut ws| async move {
+            next_text(&mut ws).await;
+            ws.send(hello("conn1")).await.unwrap();
+            // stop reading, so pings are not answered
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        })
+        .await
+        .with_ping_interval(Duration::from_millis(20))
+        .with_pong_timeout(Duration::from_millis(50));
+        let handler = Recorder::default();
+
+        let result =
+            tokio::time::timeout(Duration::from_secs(2), api.connect_to_websocket(&handler)).await;
+        assert_eq!(
+            result.unwrap().unwrap_err(),
+            ApiError::WebsocketPongTimeout(Duration::from_millis(50))
+        );
+    }
+
+    #[cfg(feature = "ws-keep-alive")]
+    #[tokio::test]
+    async fn slow_handlers_do_not_end_the_connection() {
+        let mut api = serve(|_, _, mut ws| async move {
+            next_text(&mut ws).await;
+            ws.send(hello("conn1")).await.unwrap();
+            for seq in 1..5 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                ws.send(status_change(seq)).await.unwrap();
+            }
+            ws.close(None).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await
+        .with_ping_interval(Duration::from_millis(50))
+        .with_pong_timeout(Duration::from_millis(200));
+        let handler = Recorder {
+            delay: Duration::from_millis(300),
+            ..Default::default()
+        };
+
+        api.connect_to_websocket(&handler).await.unwrap();
+
+        assert_eq!(
+            handler.calls.lock().unwrap()[6..],
+            [r#"disconnect Closed { code: None, reason: "" }"#]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_authentication_fails_the_connection() {
+        let mut api = serve(|_, _, mut ws| async move {
+            let auth: Value = serde_json::from_str(&next_text(&mut ws).await).unwrap();
+            let reply = json!({
+                "status": "FAIL",
+                "seq_reply": auth["seq"],
+                "error": { "id": "api.web_socket_router.not_authenticated.app_error", "message": "invalid session token" }
+            });
+            ws.send(Message::Text(reply.to_string())).await.unwrap();
+            ws.close(None).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await;
+
+        let err = api
+            .connect_to_websocket(&Recorder::default())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err,
+            ApiError::WebsocketAuthenticationFailed("invalid session token".to_string())
+        );
+
+        api.auth_token = None;
+        let err = api
+            .connect_to_websocket(&Recorder::default())
+            .await
+            .unwrap_err();
+        assert_eq!(err, ApiError::MissingAuthToken);
+    }
+
+    #[tokio::test]
+    async fn header_authentication_skips_the_challenge() {
+        let mut api = serve(|_, request, mut ws| async move {
+            assert_eq!(request.headers()["authorization"], "Bearer token");
+            ws.send(hello("conn1")).await.unwrap();
+            ws.close(None).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await
+        .with_websocket_header_auth(true);
+        let handler = Recorder::default();
+
+        api.connect_to_websocket(&handler).await.unwrap();
+
+        assert_eq!(
+            handler.calls.lock().unwrap()[..2],
+            ["connect conn1 resumed=false", "event 0"]
+        );
+    }
+
+    #[tokio::test]
+    async fn header_authenticated_sessions_are_resumed() {
+        let mut api = serve(|index, _, mut ws| async move {
+            if index == 0 {
+                ws.send(hello("conn1")).await.unwrap();
+            } else {
+                ws.send(status_change(1)).await.unwrap();
+            }
+            ws.close(None).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await
+        .with_websocket_header_auth(true);
+        let handler = Recorder::default();
+
+        api.connect_to_websocket(&handler).await.unwrap();
+        let resumed =
+            tokio::time::timeout(Duration::from_secs(2), api.connect_to_websocket(&handler)).await;
+        resumed.unwrap().unwrap();
+
+        assert_eq!(
+            handler.calls.lock().unwrap()[3..5],
+            ["connect conn1 resumed=true", "event 1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn websockets_are_tunneled_through_the_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("http://user:p%40ss@{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut tcp, _) = listener.accept().await.unwrap();
+            let mut connect = Vec::new();
+            while !connect.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                tcp.read_exact(&mut byte).await.unwrap();
+                connect.push(byte[0]);
+            }
+            tcp.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+
+            let mut request = Request::default();
+            // the callback signature is set by tungstenite
+            #[allow(clippy::result_large_err)]
+            let mut ws = async_tungstenite::accept_hdr_async(tcp, |req: &Request, resp| {
+                *request.headers_mut() = req.headers().clone();
+                Ok(resp)
+            })
+            .await
+            .unwrap();
+            next_text(&mut ws).await;
+            ws.send(hello("conn1")).await.unwrap();
+            ws.close(None).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+            (String::from_utf8(connect).unwrap(), request)
+        });
+        // only the proxy resolves the instance host
+        let mut api = Mattermost::new(
+            "http://mattermost.invalid",
+            AuthenticationData::from_access_token("token"),
+        )
+        .unwrap()
+        .with_transport(TransportConfig::new().proxy(proxy).websocket_header(
+            HeaderName::from_static("x-tenant"),
+            HeaderValue::from_static("blue"),
+        ))
+        .unwrap();
+
+        api.connect_to_websocket(&Recorder::default())
+            .await
+            .unwrap();
+
+        let (connect, request) = server.await.unwrap();
+        assert!(connect.starts_with("CONNECT mattermost.invalid:80 HTTP/1.1\r\n"));
+        assert!(connect.contains("Proxy-Authorization: Basic dXNlcjpwQHNz\r\n"));
+        assert_eq!(request.headers()["x-tenant"], "blue");
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_end_the_connection() {
+        let mut api = serve(|_, _, mut ws| async move {
+            next_text(&mut ws).await;
+            ws.send(hello("conn1")).await.unwrap();
+            ws.send(Message::Text("x".repeat(2048))).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await
+        .with_transport(TransportConfig::new().websocket_max_message_size(1024))
+        .unwrap();
+        let handler = Recorder::default();
+
+        let err = api.connect_to_websocket(&handler).await.unwrap_err();
+
+        assert!(
+            matches!(&err, ApiError::WebsocketError(err) if matches!(**err, tungstenite::Error::Capacity(_))),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn supervisor_reconnects_and_shuts_down_gracefully() {
+        let (connected, mut connections) = tokio::sync::mpsc::unbounded_channel();
+        let api = serve(move |index, _, mut ws| {
+            let connected = connected.clone();
+            async move {
+                next_text(&mut ws).await;
+                ws.send(hello(&format!("conn{index}"))).await.unwrap();
+                connected.send(index).unwrap();
+                if index == 0 {
+                    ws.close(None).await.unwrap();
+                }
+                while let Some(Ok(_)) = ws.next().await {}
+            }
+        })
+        .await
+        .with_reconnection_backoff(BackoffPolicy::fixed(Duration::from_millis(10)));
+        let handler = Arc::new(Recorder::default());
+
+        let supervisor = api.with_reconnection(handler.clone());
+        assert_eq!(connections.recv().await, Some(0));
+        assert_eq!(connections.recv().await, Some(1));
+        // the watch only keeps the latest state, so wait for it rather than each change
+        let mut state = supervisor.watch_state();
+        while !supervisor.is_connected() {
+            state.changed().await.unwrap();
+        }
+
+        let sender = supervisor.sender();
+        supervisor.shutdown().await;
+        assert_eq!(*state.borrow(), ConnectionState::Stopped);
+        assert!(!sender.is_connected());
+        assert_eq!(
+            handler
+                .calls
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|call| call.starts_with("connect"))
+                .collect::<Vec<_>>(),
+            ["connect conn0 resumed=false", "connect conn1 resumed=false"]
         );
     }
 }
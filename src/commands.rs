@@ -0,0 +1,578 @@
+//! Routing of bot commands sent in posted messages.
+
+use crate::{
+    client::Mattermost,
+    errors::ApiError,
+    models::{CreatePost, Post},
+    socket::{ChannelType, WebsocketEvent, WebsocketEventType, WebsocketHandler},
+};
+use async_trait::async_trait;
+use futures_util::future::{self, BoxFuture};
+use log::{debug, error};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::{self, Display},
+    future::Future,
+    str::FromStr,
+    sync::Arc,
+};
+
+/// Name of the built-in command listing the others.
+const HELP: &str = "help";
+
+/// Reply of a command, or `None` to not reply.
+type CommandResult = Result<Option<String>, ApiError>;
+
+/// Parses the arguments and starts running the command, or returns the parsing error.
+type RunCommand = Arc<
+    dyn Fn(CommandContext, &str) -> Result<BoxFuture<'static, CommandResult>, String> + Send + Sync,
+>;
+
+/// Permission check of a command.
+type Check = Arc<dyn Fn(CommandContext) -> BoxFuture<'static, bool> + Send + Sync>;
+
+/// Post invoking a command, given to its handler and permission checks.
+#[derive(Debug, Clone)]
+pub struct CommandContext {
+    /// Client to make requests with
+    pub api: Mattermost,
+    /// Post the command was sent in
+    pub post: Post,
+    /// Type of the channel of the post
+    pub channel_type: ChannelType,
+}
+
+impl CommandContext {
+    /// Id of the thread of the post, where replies are made.
+    pub fn root_id(&self) -> &str {
+        if self.post.root_id.is_empty() {
+            &self.post.id
+        } else {
+            &self.post.root_id
+        }
+    }
+
+    /// Reply in the thread of the post.
+    pub async fn reply(&self, message: impl Into<String>) -> Result<Post, ApiError> {
+        self.api
+            .create_post(CreatePost {
+                channel_id: self.post.channel_id.clone(),
+                message: message.into(),
+                root_id: Some(self.root_id().to_string()),
+                ..Default::default()
+            })
+            .await
+    }
+}
+
+/// Command that users can send to a bot.
+///
+/// Commands have a parser for their arguments, like those of this
+/// module, and a handler returning the reply to post, if any.
+///
+/// # Example
+///
+/// ```rust
+/// use mattermost_api::commands::{self, Command};
+///
+/// let deploy = Command::new(
+///     "deploy",
+///     commands::single::<String>,
+///     |_context, environment| async move { Ok(Some(format!("Deploying to {environment}"))) },
+/// )
+/// .help("Deploy the application")
+/// .usage("<environment>")
+/// .allow_users(["user_id"]);
+/// ```
+#[derive(Clone)]
+pub struct Command {
+    name: String,
+    help: String,
+    usage: String,
+    run: RunCommand,
+    checks: Vec<Check>,
+}
+
+impl fmt::Debug for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Command")
+            .field("name", &self.name)
+            .field("help", &self.help)
+            .field("usage", &self.usage)
+            .field("checks", &self.checks.len())
+            .finish()
+    }
+}
+
+impl Command {
+    /// Command `name`, with arguments parsed by `parser` and run by `handler`.
+    ///
+    /// Parsing errors are replied to the user along with the usage.
+    pub fn new<A, P, F, Fut>(name: impl Into<String>, parser: P, handler: F) -> Self
+    where
+        A: Send + 'static,
+        P: Fn(&str) -> Result<A, String> + Send + Sync + 'static,
+        F: Fn(CommandContext, A) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = CommandResult> + Send + 'static,
+    {
+        Self {
+            name: name.into().to_lowercase(),
+            help: String::new(),
+            usage: String::new(),
+            run: Arc::new(move |context, args| {
+                let args = parser(args)?;
+                Ok(Box::pin(handler(context, args)))
+            }),
+            checks: Vec::new(),
+        }
+    }
+
+    /// Description shown by the help command.
+    pub fn help(mut self, help: impl Into<String>) -> Self {
+        self.help = help.into();
+        self
+    }
+
+    /// Arguments shown by the help command, like "<environment> [version]".
+    pub fn usage(mut self, usage: impl Into<String>) -> Self {
+        self.usage = usage.into();
+        self
+    }
+
+    /// Only run the command for posts passing `check`.
+    ///
+    /// Every check must pass; otherwise the user is told they are not allowed.
+    pub fn permission<F, Fut>(mut self, check: F) -> Self
+    where
+        F: Fn(CommandContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        self.checks
+            .push(Arc::new(move |context| Box::pin(check(context))));
+        self
+    }
+
+    /// Only run the command for the given users.
+    pub fn allow_users<I, S>(self, user_ids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let user_ids: HashSet<String> = user_ids.into_iter().map(Into::into).collect();
+        self.permission(move |context| future::ready(user_ids.contains(&context.post.user_id)))
+    }
+}
+
+/// Parser for commands without arguments.
+pub fn no_args(args: &str) -> Result<(), String> {
+    if args.is_empty() {
+        Ok(())
+    } else {
+        Err("This command takes no arguments".to_string())
+    }
+}
+
+/// Parser passing the arguments as they are, possibly empty.
+pub fn rest(args: &str) -> Result<String, String> {
+    Ok(args.to_string())
+}
+
+/// Parser splitting the arguments on whitespace, keeping double-quoted ones together.
+pub fn words(args: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    for c in args.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quoted {
+        return Err("Unterminated quote".to_string());
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Parser for a single argument of type `T`.
+pub fn single<T>(args: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match words(args)?.as_slice() {
+        [arg] => arg
+            .parse()
+            .map_err(|err| format!("Invalid argument `{arg}`: {err}")),
+        [] => Err("Missing argument".to_string()),
+        _ => Err("Too many arguments".to_string()),
+    }
+}
+
+/// Handler running the commands sent to a bot in posted messages.
+///
+/// Commands are invoked with a prefix, "!" by default, or by mentioning
+/// the bot, like `!deploy prod` or `@bot deploy prod`. The bot's own posts
+/// and system messages are ignored. Replies are posted in the thread of
+/// the command. A `help` command lists the commands, unless one is
+/// registered with that name. Unknown commands are only answered when
+/// the bot is mentioned.
+///
+/// Commands run in the callback, so slow ones delay the following events;
+/// wrap the router in a `ConcurrentHandler` to avoid it.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use mattermost_api::{
+///     commands::{self, Command, CommandRouter},
+///     prelude::*,
+/// };
+/// # async fn run(mut api: Mattermost) -> Result<(), ApiError> {
+/// let me = api.get_me().await?;
+/// let router = CommandRouter::new(api.clone(), me.id, me.username).with_command(
+///     Command::new("ping", commands::no_args, |_context, ()| async {
+///         Ok(Some("pong".to_string()))
+///     })
+///     .help("Check that the bot is alive"),
+/// );
+/// api.connect_to_websocket(&router).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct CommandRouter {
+    api: Mattermost,
+    bot_user_id: String,
+    mention: String,
+    prefix: Option<String>,
+    commands: BTreeMap<String, Command>,
+}
+
+impl fmt::Debug for CommandRouter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommandRouter")
+            .field("bot_user_id", &self.bot_user_id)
+            .field("mention", &self.mention)
+            .field("prefix", &self.prefix)
+            .field("commands", &self.commands.values())
+            .finish()
+    }
+}
+
+impl CommandRouter {
+    /// Router for the bot with the given user id and username, replying with `api`.
+    pub fn new(
+        api: Mattermost,
+        bot_user_id: impl Into<String>,
+        bot_username: impl Into<String>,
+    ) -> Self {
+        Self {
+            api,
+            bot_user_id: bot_user_id.into(),
+            mention: format!("@{}", bot_username.into()),
+            prefix: Some("!".to_string()),
+            commands: BTreeMap::new(),
+        }
+    }
+
+    /// Changes the prefix invoking commands, or only accept mentions with `None`.
+    pub fn with_prefix(mut self, prefix: Option<&str>) -> Self {
+        self.prefix = prefix.map(ToString::to_string);
+        self
+    }
+
+    /// Register a command, replacing any with the same name.
+    pub fn with_command(mut self, command: Command) -> Self {
+        self.commands.insert(command.name.clone(), command);
+        self
+    }
+
+    /// Run the command sent in `post`, if any, and post its reply.
+    pub async fn dispatch(&self, post: Post, channel_type: ChannelType) -> Result<(), ApiError> {
+        if post.user_id == self.bot_user_id || !post.post_type.is_empty() {
+            return Ok(());
+        }
+        let Some((name, args, mentioned)) = self.invocation(&post.message) else {
+            return Ok(());
+        };
+        let (name, args) = (name.to_lowercase(), args.to_string());
+        debug!("Running command {name} from post {}", post.id);
+
+        let context = CommandContext {
+            api: self.api.clone(),
+            post,
+            channel_type,
+        };
+        if let Some(reply) = self.run(&context, &name, &args, mentioned).await {
+            context.reply(reply).await?;
+        }
+        Ok(())
+    }
+
+    /// Name and arguments of the command invoked by `message`, and if the bot was mentioned.
+    fn invocation<'a>(&self, message: &'a str) -> Option<(&'a str, &'a str, bool)> {
+        let message = message.trim_start();
+        let (rest, mentioned) = match self
+            .prefix
+            .as_deref()
+            .and_then(|prefix| message.strip_prefix(prefix))
+        {
+            Some(rest) => (rest, false),
+            None => {
+                let rest = message.strip_prefix(&self.mention)?;
+                if rest.starts_with(|c: char| !c.is_whitespace() && c != ':' && c != ',') {
+                    // another user whose name starts with the bot's
+                    return None;
+                }
+                (rest.trim_start_matches([':', ',']), true)
+            }
+        };
+        let rest = rest.trim();
+        let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        (!name.is_empty()).then(|| (name, args.trim(), mentioned))
+    }
+
+    /// Run the command, returning the reply to post.
+    ///
+    /// Unknown commands are only replied to when the bot was mentioned,
+    /// since messages starting with the prefix are often not meant for it.
+    async fn run(
+        &self,
+        context: &CommandContext,
+        name: &str,
+        args: &str,
+        mentioned: bool,
+    ) -> Option<String> {
+        let Some(command) = self.commands.get(name) else {
+            if name == HELP {
+                return Some(self.help(args));
+            }
+            if !mentioned {
+                debug!("Ignoring unknown command {name}");
+                return None;
+            }
+            return Some(format!(
+                "Unknown command `{name}`, see `{}` for the list of commands.",
+                self.invoke(HELP)
+            ));
+        };
+        for check in &command.checks {
+            if !check(context.clone()).await {
+                return Some(format!("You are not allowed to use `{name}`."));
+            }
+        }
+        let run = match (command.run)(context.clone(), args) {
+            Ok(run) => run,
+            Err(err) => return Some(format!("{err}. Usage: `{}`", self.usage(command))),
+        };
+        match run.await {
+            Ok(reply) => reply,
+            Err(err) => {
+                error!("Command {name} failed: {err:?}");
+                Some(format!("Command `{name}` failed."))
+            }
+        }
+    }
+
+    /// How to invoke the command `name`.
+    fn invoke(&self, name: &str) -> String {
+        match &self.prefix {
+            Some(prefix) => format!("{prefix}{name}"),
+            None => format!("{} {name}", self.mention),
+        }
+    }
+
+    fn usage(&self, command: &Command) -> String {
+        if command.usage.is_empty() {
+            self.invoke(&command.name)
+        } else {
+            format!("{} {}", self.invoke(&command.name), command.usage)
+        }
+    }
+
+    /// Help for the command `name`, or the list of commands if empty.
+    fn help(&self, name: &str) -> String {
+        let describe = |command: &Command| {
+            if command.help.is_empty() {
+                format!("`{}`", self.usage(command))
+            } else {
+                format!("`{}`: {}", self.usage(command), command.help)
+            }
+        };
+        if !name.is_empty() {
+            return match self.commands.get(&name.to_lowercase()) {
+                Some(command) => describe(command),
+                None => format!("Unknown command `{name}`."),
+            };
+        }
+        let mut help = String::from("Available commands:");
+        for command in self.commands.values() {
+            help.push_str("\n- ");
+            help.push_str(&describe(command));
+        }
+        if !self.commands.contains_key(HELP) {
+            help.push_str(&format!(
+                "\n- `{} [command]`: Show help for commands",
+                self.invoke(HELP)
+            ));
+        }
+        help
+    }
+}
+
+#[async_trait]
+impl WebsocketHandler for CommandRouter {
+    async fn callback(&self, message: WebsocketEvent) {
+        if let WebsocketEventType::Posted {
+            post, channel_type, ..
+        } = message.event
+        {
+            if let Err(err) = self.dispatch(post, channel_type).await {
+                error!("Could not reply to command: {err:?}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{no_args, single, words, Command, CommandRouter};
+    use crate::{
+        client::{AuthenticationData, Mattermost},
+        models::Post,
+        socket::ChannelType,
+    };
+    use mockito::{mock, Matcher};
+    use serde_json::json;
+
+    fn post(user_id: &str, message: &str) -> Post {
+        serde_json::from_value(json!({
+            "id": "p1", "create_at": 1, "update_at": 1, "delete_at": 0, "edit_at": 0,
+            "user_id": user_id, "channel_id": "c1", "root_id": "", "original_id": "",
+            "message": message, "type": "", "props": {}, "pending_post_id": ""
+        }))
+        .unwrap()
+    }
+
+    fn router() -> CommandRouter {
+        let api = Mattermost::new(
+            mockito::server_url(),
+            AuthenticationData::from_access_token("token"),
+        )
+        .unwrap();
+        CommandRouter::new(api, "bot_id", "bot").with_command(
+            Command::new("Add", words, |_context, numbers| async move {
+                let sum: i64 = numbers.iter().filter_map(|n| n.parse::<i64>().ok()).sum();
+                Ok(Some(sum.to_string()))
+            })
+            .help("Add numbers")
+            .usage("<numbers...>"),
+        )
+    }
+
+    #[test]
+    fn commands_are_invoked_with_the_prefix_or_a_mention() {
+        let router = router();
+
+        assert_eq!(router.invocation("!add 1 2"), Some(("add", "1 2", false)));
+        assert_eq!(
+            router.invocation("@bot: add  1 2 "),
+            Some(("add", "1 2", true))
+        );
+        assert_eq!(router.invocation("@bot help"), Some(("help", "", true)));
+        assert_eq!(router.invocation("@botanist add 1"), None);
+        assert_eq!(router.invocation("add 1"), None);
+        assert_eq!(router.invocation("!"), None);
+
+        let router = router.with_prefix(None);
+        assert_eq!(router.invocation("!add 1"), None);
+        assert_eq!(router.invocation("@bot add"), Some(("add", "", true)));
+    }
+
+    #[test]
+    fn arguments_are_parsed() {
+        assert_eq!(
+            words(r#"deploy "my app"  prod"#).unwrap(),
+            ["deploy", "my app", "prod"]
+        );
+        assert!(words(r#"deploy "my app"#).is_err());
+        assert_eq!(single::<u32>(" 42 ").unwrap(), 42);
+        assert!(single::<u32>("forty-two").is_err());
+        assert!(single::<u32>("1 2").is_err());
+    }
+
+    #[tokio::test]
+    async fn replies_are_posted_in_the_thread() {
+        let reply = |message: &str| {
+            mock("POST", "/api/v4/posts")
+                .match_body(Matcher::PartialJson(
+                    json!({ "channel_id": "c1", "root_id": "p1", "message": message }),
+                ))
+                .with_status(201)
+                .with_header("content-type", "application/json")
+                .with_body(serde_json::to_string(&post("bot_id", message)).unwrap())
+                .expect(1)
+                .create()
+        };
+        let sum = reply("6");
+        let denied = reply("You are not allowed to use `secret`.");
+        let usage = reply("Too many arguments. Usage: `!double <number>`");
+        let help = reply(
+            "Available commands:\n- `!add <numbers...>`: Add numbers\n- `!double <number>`\
+            \n- `!secret`\n- `!help [command]`: Show help for commands",
+        );
+        let unknown = reply("Unknown command `deploy`, see `!help` for the list of commands.");
+        let router = router()
+            .with_command(
+                Command::new("secret", no_args, |_, ()| async {
+                    Ok(Some("42".to_string()))
+                })
+                .allow_users(["admin"]),
+            )
+            .with_command(
+                Command::new("double", single::<i64>, |_, n| async move {
+                    Ok(Some((n * 2).to_string()))
+                })
+                .usage("<number>"),
+            );
+
+        for (user_id, message) in [
+            ("u1", "!add 1 2 3"),
+            ("bot_id", "!add 4 5"),
+            ("u1", "!secret"),
+            ("u1", "@bot double 1 2"),
+            ("u1", "!help"),
+            ("u1", "@bot deploy"),
+            // unknown commands are only answered when mentioning the bot
+            ("u1", "!important"),
+            ("u1", "!!"),
+        ] {
+            router
+                .dispatch(post(user_id, message), ChannelType::Open)
+                .await
+                .unwrap();
+        }
+
+        sum.assert();
+        denied.assert();
+        usage.assert();
+        help.assert();
+        unknown.assert();
+    }
+}
@@ -0,0 +1,720 @@
+//! State of multi-step conversations between a bot and users, kept per thread.
+
+use crate::{
+    client::Mattermost,
+    errors::ApiError,
+    models::{CreatePost, Post, Thread},
+    runtime,
+    socket::{MissedEvents, WebsocketEvent, WebsocketEventType, WebsocketHandler},
+};
+use async_trait::async_trait;
+use futures_util::future::RemoteHandle;
+use log::{debug, error};
+use std::{
+    collections::BTreeMap,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+/// How long conversations last without posts, by default.
+const DEFAULT_CONVERSATION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Thread a conversation happens in.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConversationKey {
+    /// Channel of the thread
+    pub channel_id: String,
+    /// Id of the first post of the thread
+    pub root_id: String,
+}
+
+impl ConversationKey {
+    /// Thread of `post`, which starts one if it is not a reply.
+    pub fn of_post(post: &Post) -> Self {
+        let root_id = if post.root_id.is_empty() {
+            &post.id
+        } else {
+            &post.root_id
+        };
+        Self {
+            channel_id: post.channel_id.clone(),
+            root_id: root_id.clone(),
+        }
+    }
+}
+
+/// Conversation as kept in a store.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conversation<S> {
+    /// State of the conversation, given to the handler with the next post
+    pub state: S,
+    /// When the conversation times out, unless a post is made before
+    pub expires_at: SystemTime,
+    /// Creation time of the last post handled, in milliseconds like `Post::create_at`
+    pub last_post_at: usize,
+    /// Id of the last post handled
+    pub last_post_id: String,
+}
+
+impl<S> Conversation<S> {
+    /// If `post` was made before the last post handled, or is that post.
+    ///
+    /// Posts made in the same millisecond as the last one are still handled.
+    fn has_handled(&self, post: &Post) -> bool {
+        post.create_at < self.last_post_at
+            || (post.create_at == self.last_post_at && post.id == self.last_post_id)
+    }
+}
+
+/// Storage of the conversations of a `ConversationManager`.
+///
+/// Implement it to keep conversations elsewhere than in memory,
+/// like in a database so they survive restarts. Errors can be
+/// reported with `ApiError::ConversationStoreError`.
+#[async_trait]
+pub trait ConversationStore<S: Send + 'static>: Send + Sync {
+    /// Conversation in the thread `key`, even if expired.
+    async fn get(&self, key: &ConversationKey) -> Result<Option<Conversation<S>>, ApiError>;
+
+    /// Create or replace the conversation in the thread `key`.
+    async fn set(
+        &self,
+        key: ConversationKey,
+        conversation: Conversation<S>,
+    ) -> Result<(), ApiError>;
+
+    /// Remove the conversation in the thread `key`, returning it.
+    async fn remove(&self, key: &ConversationKey) -> Result<Option<Conversation<S>>, ApiError>;
+
+    /// Threads having a conversation.
+    async fn keys(&self) -> Result<Vec<ConversationKey>, ApiError>;
+}
+
+/// Store keeping conversations in memory, ordered by thread.
+#[derive(Debug)]
+pub struct MemoryStore<S> {
+    conversations: Mutex<BTreeMap<ConversationKey, Conversation<S>>>,
+}
+
+impl<S> Default for MemoryStore<S> {
+    fn default() -> Self {
+        Self {
+            conversations: Mutex::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Clone + Send + 'static> ConversationStore<S> for MemoryStore<S> {
+    async fn get(&self, key: &ConversationKey) -> Result<Option<Conversation<S>>, ApiError> {
+        Ok(self.conversations.lock().unwrap().get(key).cloned())
+    }
+
+    async fn set(
+        &self,
+        key: ConversationKey,
+        conversation: Conversation<S>,
+    ) -> Result<(), ApiError> {
+        self.conversations.lock().unwrap().insert(key, conversation);
+        Ok(())
+    }
+
+    async fn remove(&self, key: &ConversationKey) -> Result<Option<Conversation<S>>, ApiError> {
+        Ok(self.conversations.lock().unwrap().remove(key))
+    }
+
+    async fn keys(&self) -> Result<Vec<ConversationKey>, ApiError> {
+        Ok(self.conversations.lock().unwrap().keys().cloned().collect())
+    }
+}
+
+/// What to do after handling a post in a conversation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step<S> {
+    /// Wait for the next post, with the new state
+    Continue(S),
+    /// End the conversation
+    End,
+}
+
+/// Post made in a thread, given to a `ConversationHandler`.
+#[derive(Debug, Clone)]
+pub struct ConversationContext {
+    /// Client to make requests with
+    pub api: Mattermost,
+    /// Thread of the post
+    pub key: ConversationKey,
+    /// Post being handled
+    pub post: Post,
+}
+
+impl ConversationContext {
+    /// Reply in the thread of the conversation.
+    pub async fn reply(&self, message: impl Into<String>) -> Result<Post, ApiError> {
+        self.api
+            .create_post(CreatePost {
+                channel_id: self.key.channel_id.clone(),
+                message: message.into(),
+                root_id: Some(self.key.root_id.clone()),
+                ..Default::default()
+            })
+            .await
+    }
+
+    /// All posts of the thread of the conversation.
+    pub async fn thread(&self) -> Result<Thread, ApiError> {
+        self.api.get_thread(&self.key.root_id).await
+    }
+}
+
+/// Steps of the conversations of a `ConversationManager`.
+#[async_trait]
+pub trait ConversationHandler<S: Send + 'static>: Send + Sync {
+    /// Called with posts made in threads without a conversation.
+    ///
+    /// Return a state to start a conversation in the thread.
+    async fn start(&self, _context: &ConversationContext) -> Result<Option<S>, ApiError> {
+        Ok(None)
+    }
+
+    /// Called with posts made in a conversation, and its state.
+    ///
+    /// Errors end the conversation.
+    async fn next(&self, context: &ConversationContext, state: S) -> Result<Step<S>, ApiError>;
+
+    /// Called when a conversation times out, with its last state.
+    async fn on_timeout(&self, _api: &Mattermost, _key: &ConversationKey, _state: S) {}
+}
+
+/// Handler tracking conversations per thread from `Posted` events.
+///
+/// Posts in a thread with a conversation are given to the handler along
+/// with the state of the conversation; other posts may start one. The
+/// bot's own posts and system messages are ignored. When events were
+/// missed, the threads of the conversations are fetched to handle the
+/// posts made meanwhile.
+///
+/// Conversations time out after 10 minutes without posts by default.
+/// Timeouts are only noticed when the next post is made and when `expire`
+/// is called, so call it periodically or use `expire_periodically`.
+///
+/// Posts of a thread must not be handled concurrently, so when wrapping
+/// the manager in a `ConcurrentHandler`, order events by thread.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use async_trait::async_trait;
+/// use mattermost_api::{
+///     conversation::{ConversationContext, ConversationHandler, ConversationManager, Step},
+///     prelude::*,
+/// };
+///
+/// #[derive(Clone)]
+/// enum Deploy {
+///     AskedEnvironment,
+///     AskedConfirmation(String),
+/// }
+///
+/// struct Deployer;
+///
+/// #[async_trait]
+/// impl ConversationHandler<Deploy> for Deployer {
+///     async fn start(&self, context: &ConversationContext) -> Result<Option<Deploy>, ApiError> {
+///         if context.post.message != "deploy" {
+///             return Ok(None);
+///         }
+///         context.reply("Which environment?").await?;
+///         Ok(Some(Deploy::AskedEnvironment))
+///     }
+///
+///     async fn next(
+///         &self,
+///         context: &ConversationContext,
+///         state: Deploy,
+///     ) -> Result<Step<Deploy>, ApiError> {
+///         match state {
+///             Deploy::AskedEnvironment => {
+///                 context.reply("Confirm deploy?").await?;
+///                 Ok(Step::Continue(Deploy::AskedConfirmation(context.post.message.clone())))
+///             }
+///             Deploy::AskedConfirmation(environment) => {
+///                 context.reply(format!("Deploying to {environment}")).await?;
+///                 Ok(Step::End)
+///             }
+///         }
+///     }
+/// }
+///
+/// # async fn run(mut api: Mattermost) -> Result<(), ApiError> {
+/// let me = api.get_me().await?;
+/// let conversations = ConversationManager::new(api.clone(), me.id, Deployer);
+/// api.connect_to_websocket(&conversations).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ConversationManager<S, H, St = MemoryStore<S>> {
+    api: Mattermost,
+    bot_user_id: String,
+    handler: H,
+    store: St,
+    timeout: Duration,
+    state: PhantomData<fn() -> S>,
+}
+
+impl<S, H, St> std::fmt::Debug for ConversationManager<S, H, St> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConversationManager")
+            .field("bot_user_id", &self.bot_user_id)
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, H> ConversationManager<S, H>
+where
+    S: Clone + Send + 'static,
+    H: ConversationHandler<S>,
+{
+    /// Manager for the bot with the given user id, keeping conversations in memory.
+    pub fn new(api: Mattermost, bot_user_id: impl Into<String>, handler: H) -> Self {
+        Self {
+            api,
+            bot_user_id: bot_user_id.into(),
+            handler,
+            store: MemoryStore::default(),
+            timeout: DEFAULT_CONVERSATION_TIMEOUT,
+            state: PhantomData,
+        }
+    }
+}
+
+impl<S, H, St> ConversationManager<S, H, St>
+where
+    S: Send + 'static,
+    H: ConversationHandler<S>,
+    St: ConversationStore<S>,
+{
+    /// Keep conversations in `store`.
+    pub fn with_store<T: ConversationStore<S>>(self, store: T) -> ConversationManager<S, H, T> {
+        ConversationManager {
+            api: self.api,
+            bot_user_id: self.bot_user_id,
+            handler: self.handler,
+            store,
+            timeout: self.timeout,
+            state: PhantomData,
+        }
+    }
+
+    /// Changes how long conversations last without posts.
+    ///
+    /// The default is 10 minutes.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Start a conversation in the thread of `post`, like from a command.
+    ///
+    /// `post` itself is not given to the handler.
+    pub async fn start(&self, post: &Post, state: S) -> Result<(), ApiError> {
+        self.save(ConversationKey::of_post(post), state, post).await
+    }
+
+    /// State of the conversation in the thread `key`, if one is ongoing.
+    pub async fn state(&self, key: &ConversationKey) -> Result<Option<S>, ApiError> {
+        Ok(self
+            .store
+            .get(key)
+            .await?
+            .filter(|conversation| conversation.expires_at > SystemTime::now())
+            .map(|conversation| conversation.state))
+    }
+
+    /// End the conversation in the thread `key`, returning its state.
+    pub async fn end(&self, key: &ConversationKey) -> Result<Option<S>, ApiError> {
+        Ok(self
+            .store
+            .remove(key)
+            .await?
+            .map(|conversation| conversation.state))
+    }
+
+    /// Time out the expired conversations.
+    ///
+    /// Call it periodically to notice timeouts without waiting for posts.
+    /// Conversations time out in the order of their threads.
+    pub async fn expire(&self) -> Result<(), ApiError> {
+        let now = SystemTime::now();
+        let mut keys = self.store.keys().await?;
+        keys.sort();
+        for key in keys {
+            if let Some(conversation) = self.store.get(&key).await? {
+                if conversation.expires_at <= now {
+                    self.time_out(&key).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Call `expire` every `interval` in a background task.
+    ///
+    /// The task stops when the returned handle is dropped. Errors are logged.
+    pub fn expire_periodically(self: &Arc<Self>, interval: Duration) -> ExpiryTask
+    where
+        Self: 'static,
+    {
+        let manager = Arc::downgrade(self);
+        let task = runtime::spawn_with_handle(async move {
+            loop {
+                runtime::sleep(interval).await;
+                let Some(manager) = manager.upgrade() else {
+                    return;
+                };
+                if let Err(err) = manager.expire().await {
+                    error!("Could not expire conversations: {err:?}");
+                }
+            }
+        });
+        ExpiryTask { _task: task }
+    }
+
+    /// Handle a post, as received in a `Posted` event.
+    pub async fn handle_post(&self, post: Post) -> Result<(), ApiError> {
+        if post.user_id == self.bot_user_id || !post.post_type.is_empty() {
+            return Ok(());
+        }
+        let key = ConversationKey::of_post(&post);
+        let state = match self.store.get(&key).await? {
+            Some(conversation) if conversation.expires_at <= SystemTime::now() => {
+                self.time_out(&key).await?;
+                None
+            }
+            // already handled when catching up
+            Some(conversation) if conversation.has_handled(&post) => return Ok(()),
+            Some(conversation) => Some(conversation.state),
+            None => None,
+        };
+
+        let context = ConversationContext {
+            api: self.api.clone(),
+            key: key.clone(),
+            post,
+        };
+        let step = match state {
+            Some(state) => self.handler.next(&context, state).await,
+            None => match self.handler.start(&context).await? {
+                Some(state) => Ok(Step::Continue(state)),
+                None => return Ok(()),
+            },
+        };
+        match step {
+            Ok(Step::Continue(state)) => self.save(key, state, &context.post).await,
+            Ok(Step::End) => {
+                debug!("Conversation in thread {} ended", key.root_id);
+                self.store.remove(&key).await.map(drop)
+            }
+            Err(err) => {
+                self.store.remove(&key).await?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Handle the posts made in the threads of conversations since their last handled post.
+    ///
+    /// Called when websocket events were missed. Threads that cannot be
+    /// caught up are logged and skipped, so that the others still are.
+    pub async fn catch_up(&self) -> Result<(), ApiError> {
+        for key in self.store.keys().await? {
+            if let Err(err) = self.catch_up_thread(&key).await {
+                error!(
+                    "Could not catch up on the conversation in thread {}: {err:?}",
+                    key.root_id
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn catch_up_thread(&self, key: &ConversationKey) -> Result<(), ApiError> {
+        let Some(conversation) = self.store.get(key).await? else {
+            return Ok(());
+        };
+        let thread = self.api.get_thread(&key.root_id).await?;
+        let mut posts: Vec<_> = thread
+            .posts
+            .into_values()
+            .filter(|post| !conversation.has_handled(post))
+            .collect();
+        posts.sort_by_key(|post| post.create_at);
+        for post in posts {
+            self.handle_post(post).await?;
+        }
+        Ok(())
+    }
+
+    async fn save(&self, key: ConversationKey, state: S, last_post: &Post) -> Result<(), ApiError> {
+        let conversation = Conversation {
+            state,
+            expires_at: SystemTime::now() + self.timeout,
+            last_post_at: last_post.create_at,
+            last_post_id: last_post.id.clone(),
+        };
+        self.store.set(key, conversation).await
+    }
+
+    async fn time_out(&self, key: &ConversationKey) -> Result<(), ApiError> {
+        if let Some(conversation) = self.store.remove(key).await? {
+            debug!("Conversation in thread {} timed out", key.root_id);
+            self.handler
+                .on_timeout(&self.api, key, conversation.state)
+                .await;
+        }
+        Ok(())
+    }
+}
+
+/// Background task expiring conversations, from `ConversationManager::expire_periodically`.
+///
+/// Dropping it stops the task.
+#[derive(Debug)]
+pub struct ExpiryTask {
+    _task: RemoteHandle<()>,
+}
+
+#[async_trait]
+impl<S, H, St> WebsocketHandler for ConversationManager<S, H, St>
+where
+    S: Send + 'static,
+    H: ConversationHandler<S>,
+    St: ConversationStore<S>,
+{
+    async fn callback(&self, message: WebsocketEvent) {
+        if let WebsocketEventType::Posted { post, .. } = message.event {
+            if let Err(err) = self.handle_post(post).await {
+                error!("Could not handle post in conversation: {err:?}");
+            }
+        }
+    }
+
+    async fn on_missed_events(&self, _missed: MissedEvents) {
+        if let Err(err) = self.catch_up().await {
+            error!("Could not catch up on conversations: {err:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ConversationContext, ConversationHandler, ConversationKey, ConversationManager, Step,
+    };
+    use crate::{
+        client::{AuthenticationData, Mattermost},
+        errors::ApiError,
+        models::Post,
+    };
+    use async_trait::async_trait;
+    use mockito::mock;
+    use serde_json::json;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    fn post(id: &str, root_id: &str, user_id: &str, message: &str, create_at: usize) -> Post {
+        serde_json::from_value(json!({
+            "id": id, "create_at": create_at, "update_at": create_at, "delete_at": 0,
+            "edit_at": 0, "user_id": user_id, "channel_id": "c1", "root_id": root_id,
+            "original_id": "", "message": message, "type": "", "props": {},
+            "pending_post_id": ""
+        }))
+        .unwrap()
+    }
+
+    /// Handler counting the posts of conversations, started by "start" and ended by "end".
+    #[derive(Default)]
+    struct Counter {
+        timeouts: Mutex<Vec<usize>>,
+    }
+
+    #[async_trait]
+    impl ConversationHandler<usize> for Counter {
+        async fn start(&self, context: &ConversationContext) -> Result<Option<usize>, ApiError> {
+            Ok((context.post.message == "start").then_some(0))
+        }
+
+        async fn next(
+            &self,
+            context: &ConversationContext,
+            count: usize,
+        ) -> Result<Step<usize>, ApiError> {
+            match context.post.message.as_str() {
+                "end" => Ok(Step::End),
+                _ => Ok(Step::Continue(count + 1)),
+            }
+        }
+
+        async fn on_timeout(&self, _api: &Mattermost, _key: &ConversationKey, count: usize) {
+            self.timeouts.lock().unwrap().push(count);
+        }
+    }
+
+    fn manager() -> ConversationManager<usize, Counter> {
+        let api = Mattermost::new(
+            mockito::server_url(),
+            AuthenticationData::from_access_token("token"),
+        )
+        .unwrap();
+        ConversationManager::new(api, "bot_id", Counter::default())
+    }
+
+    fn key(root_id: &str) -> ConversationKey {
+        ConversationKey {
+            channel_id: "c1".to_string(),
+            root_id: root_id.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn conversations_are_tracked_per_thread() {
+        let manager = manager();
+        for post in [
+            post("p1", "", "u1", "start", 1),
+            post("p2", "p1", "u1", "one", 2),
+            post("p3", "p1", "bot_id", "ignored", 3),
+            post("p4", "", "u1", "other thread", 4),
+            post("p5", "p1", "u1", "two", 5),
+        ] {
+            manager.handle_post(post).await.unwrap();
+        }
+
+        assert_eq!(manager.state(&key("p1")).await.unwrap(), Some(2));
+        assert_eq!(manager.state(&key("p4")).await.unwrap(), None);
+
+        manager
+            .handle_post(post("p6", "p1", "u1", "end", 6))
+            .await
+            .unwrap();
+        assert_eq!(manager.state(&key("p1")).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn posts_made_in_the_same_millisecond_are_handled() {
+        let manager = manager();
+        for post in [
+            post("p1", "", "u1", "start", 1),
+            post("p2", "p1", "u1", "one", 2),
+            post("p3", "p1", "u1", "two", 2),
+            // received again
+            post("p3", "p1", "u1", "two", 2),
+        ] {
+            manager.handle_post(post).await.unwrap();
+        }
+
+        assert_eq!(manager.state(&key("p1")).await.unwrap(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn idle_conversations_time_out() {
+        let manager = manager().with_timeout(Duration::ZERO);
+        manager
+            .start(&post("p1", "", "u1", "!deploy", 1), 5)
+            .await
+            .unwrap();
+        manager
+            .handle_post(post("p2", "", "u1", "start", 2))
+            .await
+            .unwrap();
+
+        manager.expire().await.unwrap();
+        // in the order of the threads
+        assert_eq!(*manager.handler.timeouts.lock().unwrap(), [5, 0]);
+        assert_eq!(manager.state(&key("p1")).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn conversations_are_expired_periodically() {
+        let manager = Arc::new(manager().with_timeout(Duration::ZERO));
+        manager
+            .start(&post("p1", "", "u1", "!deploy", 1), 5)
+            .await
+            .unwrap();
+
+        let task = manager.expire_periodically(Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        drop(task);
+
+        assert_eq!(*manager.handler.timeouts.lock().unwrap(), [5]);
+    }
+
+    #[tokio::test]
+    async fn missed_posts_are_caught_up_from_the_thread() {
+        let thread = json!({
+            "order": ["p3", "p2", "p1"],
+            "posts": {
+                "p1": post("p1", "", "u1", "!count", 1),
+                "p2": post("p2", "p1", "u1", "one", 2),
+                "p3": post("p3", "p1", "u1", "two", 3),
+            },
+            "next_post_id": "", "prev_post_id": "", "has_next": false
+        });
+        let _m = mock("GET", "/api/v4/posts/p1/thread")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(thread.to_string())
+            .create();
+        let manager = manager();
+        manager
+            .start(&post("p1", "", "u1", "!count", 1), 0)
+            .await
+            .unwrap();
+
+        manager.catch_up().await.unwrap();
+        // posts already handled are skipped
+        manager
+            .handle_post(post("p3", "p1", "u1", "two", 3))
+            .await
+            .unwrap();
+
+        assert_eq!(manager.state(&key("p1")).await.unwrap(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn catching_up_continues_after_failed_threads() {
+        let thread = json!({
+            "order": ["q5", "q4"],
+            "posts": {
+                "q4": post("q4", "", "u1", "!count", 1),
+                "q5": post("q5", "q4", "u1", "one", 2),
+            },
+            "next_post_id": "", "prev_post_id": "", "has_next": false
+        });
+        let _failed = mock("GET", "/api/v4/posts/q1/thread")
+            .with_status(404)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"id":"app.post.get.app_error","message":"not found","detailed_error":"","request_id":"r","status_code":404}"#)
+            .create();
+        let _m = mock("GET", "/api/v4/posts/q4/thread")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(thread.to_string())
+            .create();
+        let manager = manager();
+        for root in ["q1", "q4"] {
+            manager
+                .start(&post(root, "", "u1", "!count", 1), 0)
+                .await
+                .unwrap();
+        }
+
+        manager.catch_up().await.unwrap();
+
+        assert_eq!(manager.state(&key("q1")).await.unwrap(), Some(0));
+        assert_eq!(manager.state(&key("q4")).await.unwrap(), Some(1));
+    }
+}
@@ -0,0 +1,614 @@
+//! Dispatching websocket events from one connection to several consumers.
+
+use crate::{
+    errors::ApiError,
+    filter::EventFilter,
+    runtime,
+    socket::{
+        ConnectionInfo, DisconnectReason, MissedEvents, WebsocketEvent, WebsocketEventType,
+        WebsocketHandler,
+    },
+};
+use async_trait::async_trait;
+use log::{debug, warn};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::{broadcast, mpsc, oneshot, Semaphore};
+
+/// Number of events a subscription can lag behind before missing some.
+const DEFAULT_SUBSCRIPTION_CAPACITY: usize = 256;
+
+/// How long a worker of `ConcurrentHandler` waits for events before stopping.
+const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Handler dispatching events to several handlers and subscriptions.
+///
+/// Every handler and subscription has its own filter, applied after
+/// the client's event filter. Connection lifecycle hooks are called
+/// on all handlers.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use mattermost_api::{dispatch::HandlerSet, filter::EventFilter, prelude::*};
+/// # struct Moderation;
+/// # impl WebsocketHandler for Moderation {}
+/// # struct Metrics;
+/// # impl WebsocketHandler for Metrics {}
+/// # async fn run(mut api: Mattermost) {
+/// let handlers = HandlerSet::new()
+///     .with_filtered_handler(EventFilter::new().event_types(["posted"]), Moderation)
+///     .with_handler(Metrics);
+/// let mut reminders = handlers.subscribe(EventFilter::new().channels(["channel_id"]));
+/// tokio::spawn(async move {
+///     while let Ok(event) = reminders.recv().await {
+///         println!("{event:?}");
+///     }
+/// });
+/// api.connect_to_websocket(&handlers).await.unwrap();
+/// # }
+/// ```
+pub struct HandlerSet {
+    handlers: Vec<(EventFilter, Box<dyn WebsocketHandler>)>,
+    subscriptions: Mutex<Vec<(EventFilter, broadcast::Sender<WebsocketEvent>)>>,
+    subscription_capacity: usize,
+}
+
+impl Default for HandlerSet {
+    fn default() -> Self {
+        Self {
+            handlers: Vec::new(),
+            subscriptions: Mutex::default(),
+            subscription_capacity: DEFAULT_SUBSCRIPTION_CAPACITY,
+        }
+    }
+}
+
+impl std::fmt::Debug for HandlerSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HandlerSet")
+            .field("handlers", &self.handlers.len())
+            .field("subscriptions", &self.subscriptions.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl HandlerSet {
+    /// Set without handlers or subscriptions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a handler receiving all events.
+    pub fn with_handler<H: WebsocketHandler + 'static>(self, handler: H) -> Self {
+        self.with_filtered_handler(EventFilter::new(), handler)
+    }
+
+    /// Add a handler receiving the events matching `filter`.
+    pub fn with_filtered_handler<H: WebsocketHandler + 'static>(
+        mut self,
+        filter: EventFilter,
+        handler: H,
+    ) -> Self {
+        self.handlers.push((filter, Box::new(handler)));
+        self
+    }
+
+    /// Changes how many events subscriptions made afterwards can lag behind.
+    ///
+    /// The default is 256 events; lagging receivers miss the oldest events.
+    pub fn with_subscription_capacity(mut self, capacity: usize) -> Self {
+        self.subscription_capacity = capacity;
+        self
+    }
+
+    /// Subscribe to the events matching `filter`.
+    ///
+    /// Subscriptions can be made while connected, by sharing
+    /// the set in an `Arc`. Receivers cloned with `resubscribe`
+    /// share the subscription.
+    pub fn subscribe(&self, filter: EventFilter) -> broadcast::Receiver<WebsocketEvent> {
+        let (sender, receiver) = broadcast::channel(self.subscription_capacity);
+        self.subscriptions.lock().unwrap().push((filter, sender));
+        receiver
+    }
+
+    /// Send the event to the subscriptions matching it, dropping closed ones.
+    fn publish(&self, event: &WebsocketEvent) {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .retain(|(filter, sender)| {
+                if sender.receiver_count() == 0 {
+                    debug!("Dropping closed websocket event subscription");
+                    return false;
+                }
+                if filter.matches(event) {
+                    // only fails if all receivers were dropped meanwhile
+                    let _ = sender.send(event.clone());
+                }
+                true
+            });
+    }
+}
+
+#[async_trait]
+impl WebsocketHandler for HandlerSet {
+    async fn callback(&self, message: WebsocketEvent) {
+        self.publish(&message);
+        for (filter, handler) in &self.handlers {
+            if filter.matches(&message) {
+                handler.callback(message.clone()).await;
+            }
+        }
+    }
+
+    async fn on_missed_events(&self, missed: MissedEvents) {
+        for (_, handler) in &self.handlers {
+            handler.on_missed_events(missed.clone()).await;
+        }
+    }
+
+    async fn on_connect(&self, info: ConnectionInfo) {
+        for (_, handler) in &self.handlers {
+            handler.on_connect(info.clone()).await;
+        }
+    }
+
+    async fn on_disconnect(&self, reason: DisconnectReason) {
+        for (_, handler) in &self.handlers {
+            handler.on_disconnect(reason.clone()).await;
+        }
+    }
+
+    async fn on_error(&self, error: &ApiError) {
+        for (_, handler) in &self.handlers {
+            handler.on_error(error).await;
+        }
+    }
+
+    async fn on_parse_error(&self, text: &str) {
+        for (_, handler) in &self.handlers {
+            handler.on_parse_error(text).await;
+        }
+    }
+}
+
+/// Events whose order is preserved by `ConcurrentHandler`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderBy {
+    /// Events of the same channel are handled in order
+    Channel,
+    /// Events of the same post thread are handled in order;
+    /// other events are ordered by channel
+    Thread,
+}
+
+/// What `ConcurrentHandler` does with an event when its queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for room in the queue, which stops receiving events meanwhile
+    Block,
+    /// Drop the event
+    DropNewest,
+}
+
+/// Item of the queue of a `ConcurrentHandler` worker.
+#[derive(Debug)]
+// nearly all jobs are events, boxing them would only add allocations
+#[allow(clippy::large_enum_variant)]
+enum Job {
+    /// Event to handle
+    Event(WebsocketEvent),
+    /// Signal sent once the events queued before have been handled
+    Flush(oneshot::Sender<()>),
+}
+
+/// Queue of events for a worker, with the id of the worker.
+type WorkerQueue = (u64, mpsc::Sender<Job>);
+
+/// Handler wrapper calling the wrapped handler concurrently.
+///
+/// Events are queued by key, as set with `with_order_by`, and each
+/// queue is handled by its own task, so a slow callback only delays
+/// events with the same key. `on_disconnect` and `on_missed_events`
+/// wait for the queued events to be handled before being called, so
+/// they are seen after the events received before them; the other
+/// lifecycle hooks are called directly.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use mattermost_api::{dispatch::{ConcurrentHandler, OrderBy, OverflowPolicy}, prelude::*};
+/// # struct Handler;
+/// # impl WebsocketHandler for Handler {}
+/// # async fn run(mut api: Mattermost) {
+/// let handler = ConcurrentHandler::new(Handler)
+///     .with_max_concurrency(8)
+///     .with_order_by(OrderBy::Thread)
+///     .with_queue_size(100)
+///     .with_overflow_policy(OverflowPolicy::DropNewest);
+/// api.connect_to_websocket(&handler).await.unwrap();
+/// # }
+/// ```
+pub struct ConcurrentHandler<H> {
+    handler: Arc<H>,
+    semaphore: Arc<Semaphore>,
+    order_by: OrderBy,
+    queue_size: usize,
+    overflow_policy: OverflowPolicy,
+    workers: Arc<tokio::sync::Mutex<HashMap<String, WorkerQueue>>>,
+    next_worker_id: AtomicU64,
+}
+
+impl<H> std::fmt::Debug for ConcurrentHandler<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConcurrentHandler")
+            .field("order_by", &self.order_by)
+            .field("queue_size", &self.queue_size)
+            .field("overflow_policy", &self.overflow_policy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<H: WebsocketHandler + 'static> ConcurrentHandler<H> {
+    /// Wrap `handler`, with at most 16 concurrent callbacks, ordering
+    /// events by channel, and blocking once 64 events of a channel are queued.
+    pub fn new(handler: H) -> Self {
+        Self {
+            handler: Arc::new(handler),
+            semaphore: Arc::new(Semaphore::new(16)),
+            order_by: OrderBy::Channel,
+            queue_size: 64,
+            overflow_policy: OverflowPolicy::Block,
+            workers: Arc::default(),
+            next_worker_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Changes the maximum number of callbacks running at the same time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_concurrency` is 0.
+    pub fn with_max_concurrency(mut self, max_concurrency: usize) -> Self {
+        assert!(max_concurrency > 0, "max concurrency must be positive");
+        self.semaphore = Arc::new(Semaphore::new(max_concurrency));
+        self
+    }
+
+    /// Changes which events are handled in order.
+    pub fn with_order_by(mut self, order_by: OrderBy) -> Self {
+        self.order_by = order_by;
+        self
+    }
+
+    /// Changes how many events can be queued for each key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `queue_size` is 0.
+    pub fn with_queue_size(mut self, queue_size: usize) -> Self {
+        assert!(queue_size > 0, "queue size must be positive");
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Changes what happens to events when their queue is full.
+    pub fn with_overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+
+    /// Key of the queue the event goes to.
+    fn key(&self, event: &WebsocketEvent) -> String {
+        if self.order_by == OrderBy::Thread {
+            if let WebsocketEventType::Posted { post, .. }
+            | WebsocketEventType::PostEdited { post }
+            | WebsocketEventType::PostDeleted { post, .. } = &event.event
+            {
+                let root_id = if post.root_id.is_empty() {
+                    &post.id
+                } else {
+                    &post.root_id
+                };
+                return format!("{}/{root_id}", event.broadcast.channel_id);
+            }
+        }
+        event.broadcast.channel_id.clone()
+    }
+
+    /// Start a worker handling the events of `key`, returning its queue.
+    fn spawn_worker(&self, key: String) -> WorkerQueue {
+        let id = self.next_worker_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, mut receiver) = mpsc::channel(self.queue_size);
+        let handler = self.handler.clone();
+        let semaphore = self.semaphore.clone();
+        let workers = self.workers.clone();
+
+        runtime::spawn(async move {
+            loop {
+                let job = match runtime::timeout(WORKER_IDLE_TIMEOUT, receiver.recv()).await {
+                    Ok(Some(job)) => job,
+                    Ok(None) => return,
+                    Err(_) => {
+                        // events are only queued while the lock is held,
+                        // so none can arrive once the queue is removed
+                        let Ok(mut workers) = workers.try_lock() else {
+                            continue;
+                        };
+                        match receiver.try_recv() {
+                            Ok(job) => {
+                                drop(workers);
+                                job
+                            }
+                            Err(_) => {
+                                if workers.get(&key).is_some_and(|(worker, _)| *worker == id) {
+                                    debug!("Stopping idle websocket handler worker for {key:?}");
+                                    workers.remove(&key);
+                                }
+                                return;
+                            }
+                        }
+                    }
+                };
+                let event = match job {
+                    Job::Event(event) => event,
+                    Job::Flush(done) => {
+                        let _ = done.send(());
+                        continue;
+                    }
+                };
+                let Ok(_permit) = semaphore.acquire().await else {
+                    return;
+                };
+                handler.callback(event).await;
+            }
+        });
+        (id, sender)
+    }
+
+    /// Wait for the events queued so far to be handled.
+    async fn flush(&self) {
+        let mut flushed = Vec::new();
+        for (_, queue) in self.workers.lock().await.values() {
+            let (done, receiver) = oneshot::channel();
+            // a stopped worker has nothing left to handle
+            if queue.send(Job::Flush(done)).await.is_ok() {
+                flushed.push(receiver);
+            }
+        }
+        for receiver in flushed {
+            let _ = receiver.await;
+        }
+    }
+}
+
+#[async_trait]
+impl<H: WebsocketHandler + 'static> WebsocketHandler for ConcurrentHandler<H> {
+    /// Queue the event, returning once it is queued.
+    async fn callback(&self, message: WebsocketEvent) {
+        let key = self.key(&message);
+        let mut workers = self.workers.lock().await;
+        let (_, queue) = workers
+            .entry(key.clone())
+            .or_insert_with(|| self.spawn_worker(key.clone()));
+
+        let seq = message.seq;
+        let job = Job::Event(message);
+        let result = match self.overflow_policy {
+            OverflowPolicy::Block => queue.send(job).await.map_err(|err| err.0),
+            OverflowPolicy::DropNewest => match queue.try_send(job) {
+                Ok(()) => Ok(()),
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    warn!("Websocket handler queue for {key:?} is full, dropping event {seq}");
+                    Ok(())
+                }
+                Err(mpsc::error::TrySendError::Closed(job)) => Err(job),
+            },
+        };
+        // the worker stopped, which only happens if it panicked
+        if let Err(job) = result {
+            warn!("Websocket handler worker for {key:?} stopped, restarting it");
+            let worker = self.spawn_worker(key.clone());
+            // the new queue is empty, so this cannot fail
+            let _ = worker.1.try_send(job);
+            workers.insert(key, worker);
+        }
+    }
+
+    async fn on_missed_events(&self, missed: MissedEvents) {
+        self.flush().await;
+        self.handler.on_missed_events(missed).await
+    }
+
+    async fn on_connect(&self, info: ConnectionInfo) {
+        self.handler.on_connect(info).await
+    }
+
+    async fn on_disconnect(&self, reason: DisconnectReason) {
+        self.flush().await;
+        self.handler.on_disconnect(reason).await
+    }
+
+    async fn on_error(&self, error: &ApiError) {
+        self.handler.on_error(error).await
+    }
+
+    async fn on_parse_error(&self, text: &str) {
+        self.handler.on_parse_error(text).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConcurrentHandler, HandlerSet, OverflowPolicy};
+    use crate::{
+        filter::EventFilter,
+        socket::{DisconnectReason, WebsocketEvent, WebsocketHandler},
+    };
+    use async_trait::async_trait;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tokio::sync::Semaphore;
+
+    fn event(name: &str, seq: usize) -> WebsocketEvent {
+        in_channel(name, "c1", seq)
+    }
+
+    fn in_channel(name: &str, channel_id: &str, seq: usize) -> WebsocketEvent {
+        serde_json::from_str(&format!(
+            r#"{{"event":"{name}","data":{{"status":"online","user_id":"u1"}},
+            "broadcast":{{"omit_users":null,"user_id":"u1","channel_id":"{channel_id}","team_id":"t1"}},"seq":{seq}}}"#
+        ))
+        .unwrap()
+    }
+
+    /// Handler waiting for a permit before handling events of channel "c1".
+    struct Gated {
+        gate: Semaphore,
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl Default for Gated {
+        fn default() -> Self {
+            Self {
+                gate: Semaphore::new(0),
+                calls: Mutex::default(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl WebsocketHandler for Gated {
+        async fn callback(&self, message: WebsocketEvent) {
+            if message.broadcast.channel_id == "c1" {
+                self.gate.acquire().await.unwrap().forget();
+            }
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", message.broadcast.channel_id, message.seq));
+        }
+
+        async fn on_disconnect(&self, _reason: DisconnectReason) {
+            self.calls.lock().unwrap().push("disconnect".to_string());
+        }
+    }
+
+    async fn wait_for_calls(handler: &Gated, count: usize) -> Vec<String> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let calls = handler.calls.lock().unwrap().clone();
+                if calls.len() >= count {
+                    return calls;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    struct Recorder(&'static str, Arc<Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl WebsocketHandler for Recorder {
+        async fn callback(&self, message: WebsocketEvent) {
+            self.1
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", self.0, message.seq));
+        }
+    }
+
+    #[tokio::test]
+    async fn events_are_fanned_out_through_filters() {
+        let calls = Arc::new(Mutex::default());
+        let handlers = HandlerSet::new()
+            .with_handler(Recorder("all", calls.clone()))
+            .with_filtered_handler(
+                EventFilter::new().event_types(["status_change"]),
+                Recorder("status", calls.clone()),
+            );
+        let mut subscription =
+            handlers.subscribe(EventFilter::new().event_types(["some_future_event"]));
+        drop(handlers.subscribe(EventFilter::new()));
+
+        handlers.callback(event("status_change", 1)).await;
+        handlers.callback(event("some_future_event", 2)).await;
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["all 1", "status 1", "all 2"].map(String::from)
+        );
+        assert_eq!(subscription.recv().await.unwrap().seq, 2);
+        assert!(subscription.try_recv().is_err());
+        assert_eq!(handlers.subscriptions.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn slow_channels_do_not_block_others_and_stay_ordered() {
+        let gated = Arc::new(Gated::default());
+        let handler = ConcurrentHandler::new(gated.clone());
+
+        handler.callback(in_channel("status_change", "c1", 1)).await;
+        handler.callback(in_channel("status_change", "c1", 2)).await;
+        handler.callback(in_channel("status_change", "c2", 3)).await;
+        assert_eq!(wait_for_calls(&gated, 1).await, ["c2 3"]);
+
+        gated.gate.add_permits(2);
+        assert_eq!(wait_for_calls(&gated, 3).await, ["c2 3", "c1 1", "c1 2"]);
+    }
+
+    #[tokio::test]
+    async fn full_queues_drop_new_events() {
+        let gated = Arc::new(Gated::default());
+        let handler = ConcurrentHandler::new(gated.clone())
+            .with_queue_size(1)
+            .with_overflow_policy(OverflowPolicy::DropNewest);
+
+        handler.callback(in_channel("status_change", "c1", 1)).await;
+        // let the worker take the first event off the queue
+        while handler.workers.lock().await["c1"].1.capacity() == 0 {
+            tokio::task::yield_now().await;
+        }
+        handler.callback(in_channel("status_change", "c1", 2)).await;
+        handler.callback(in_channel("status_change", "c1", 3)).await;
+
+        gated.gate.add_permits(3);
+        assert_eq!(wait_for_calls(&gated, 2).await, ["c1 1", "c1 2"]);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(gated.calls.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn disconnect_is_reported_after_queued_events() {
+        let gated = Arc::new(Gated::default());
+        let handler = Arc::new(ConcurrentHandler::new(gated.clone()));
+
+        handler.callback(in_channel("status_change", "c1", 1)).await;
+        let disconnect = tokio::spawn({
+            let handler = handler.clone();
+            async move {
+                handler
+                    .on_disconnect(DisconnectReason::Error("gone".to_string()))
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!disconnect.is_finished());
+
+        gated.gate.add_permits(1);
+        disconnect.await.unwrap();
+        assert_eq!(*gated.calls.lock().unwrap(), ["c1 1", "disconnect"]);
+    }
+}
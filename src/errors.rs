@@ -29,4 +29,28 @@ pub enum ApiError {
     WebsocketError(#[from] Box<async_tungstenite::tungstenite::Error>),
     #[error("Url parsing error")]
     UrlError(#[from] url::ParseError),
+    #[error("I/O error")]
+    IoError(#[from] std::io::Error),
+    #[error("Upload data ended after {uploaded} of {expected} bytes")]
+    IncompleteUpload { uploaded: u64, expected: u64 },
+    #[error("No websocket connection is open")]
+    WebsocketNotConnected,
+    #[error("Websocket action failed: {0}")]
+    WebsocketActionError(String),
+    #[error("Websocket event {expected} was missed, got event {received}")]
+    WebsocketSequenceGap { expected: u64, received: u64 },
+    #[error("Websocket authentication failed: {0}")]
+    WebsocketAuthenticationFailed(String),
+    #[error("No response from the websocket server in {0:?}")]
+    WebsocketPongTimeout(std::time::Duration),
+    #[error("Websocket proxy error: {0}")]
+    WebsocketProxyError(String),
+    #[error("Invalid TLS configuration: {0}")]
+    TlsConfigError(String),
+    #[error("Conversation store error: {0}")]
+    ConversationStoreError(String),
+    #[error("Upload chunk size must not be zero")]
+    InvalidChunkSize,
+    #[error("Operation timed out")]
+    Timeout,
 }
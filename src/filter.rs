@@ -0,0 +1,216 @@
+//! Filtering of websocket events before they are dispatched.
+
+use crate::socket::{WebsocketEvent, WebsocketEventBroadcast};
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{Arc, RwLock},
+};
+
+/// Predicate over the broadcast information of an event.
+type BroadcastPredicate = Arc<dyn Fn(&WebsocketEventBroadcast) -> bool + Send + Sync>;
+
+/// Filter selecting which websocket events are dispatched.
+///
+/// The Mattermost server sends every event the user may see, so
+/// the filter is applied by the client, before events reach handlers.
+/// An event passes if it matches every criterion that was set;
+/// the default filter lets all events through.
+///
+/// # Example
+///
+/// ```rust
+/// use mattermost_api::filter::EventFilter;
+///
+/// let filter = EventFilter::new()
+///     .event_types(["posted", "post_edited"])
+///     .channels(["channel_id_1", "channel_id_2"]);
+/// ```
+#[derive(Clone, Default)]
+pub struct EventFilter {
+    event_types: Option<HashSet<String>>,
+    channel_ids: Option<HashSet<String>>,
+    team_ids: Option<HashSet<String>>,
+    user_ids: Option<HashSet<String>>,
+    predicate: Option<BroadcastPredicate>,
+}
+
+impl fmt::Debug for EventFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventFilter")
+            .field("event_types", &self.event_types)
+            .field("channel_ids", &self.channel_ids)
+            .field("team_ids", &self.team_ids)
+            .field("user_ids", &self.user_ids)
+            .field("predicate", &self.predicate.is_some())
+            .finish()
+    }
+}
+
+fn to_set<I, S>(values: I) -> Option<HashSet<String>>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    Some(values.into_iter().map(Into::into).collect())
+}
+
+impl EventFilter {
+    /// Filter letting all events through.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only let through events of the given types, by name as sent by the server, like "posted".
+    pub fn event_types<I, S>(mut self, event_types: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.event_types = to_set(event_types);
+        self
+    }
+
+    /// Only let through events broadcast to the given channels.
+    pub fn channels<I, S>(mut self, channel_ids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.channel_ids = to_set(channel_ids);
+        self
+    }
+
+    /// Only let through events broadcast to the given teams.
+    pub fn teams<I, S>(mut self, team_ids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.team_ids = to_set(team_ids);
+        self
+    }
+
+    /// Only let through events broadcast to the given users.
+    pub fn users<I, S>(mut self, user_ids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.user_ids = to_set(user_ids);
+        self
+    }
+
+    /// Only let through events whose broadcast information matches `predicate`.
+    pub fn predicate<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&WebsocketEventBroadcast) -> bool + Send + Sync + 'static,
+    {
+        self.predicate = Some(Arc::new(predicate));
+        self
+    }
+
+    /// If the event passes the filter.
+    pub fn matches(&self, event: &WebsocketEvent) -> bool {
+        let allowed = |set: &Option<HashSet<String>>, value: &str| {
+            set.as_ref().is_none_or(|set| set.contains(value))
+        };
+        let broadcast = &event.broadcast;
+
+        allowed(&self.event_types, event.event.name())
+            && allowed(&self.channel_ids, &broadcast.channel_id)
+            && allowed(&self.team_ids, &broadcast.team_id)
+            && allowed(
+                &self.user_ids,
+                broadcast.user_id.as_deref().unwrap_or_default(),
+            )
+            && self
+                .predicate
+                .as_ref()
+                .is_none_or(|predicate| predicate(broadcast))
+    }
+}
+
+/// Handle for changing the event filter of a client at runtime.
+///
+/// Get one with `Mattermost::event_filter`. Changes apply to
+/// open connections, from the next event received.
+#[derive(Debug, Clone, Default)]
+pub struct EventFilterHandle {
+    filter: Arc<RwLock<EventFilter>>,
+}
+
+impl EventFilterHandle {
+    /// Current filter.
+    pub fn get(&self) -> EventFilter {
+        self.filter.read().unwrap().clone()
+    }
+
+    /// Replace the filter.
+    pub fn set(&self, filter: EventFilter) {
+        *self.filter.write().unwrap() = filter;
+    }
+
+    /// Change the filter in place.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use mattermost_api::prelude::*;
+    /// # fn run(api: Mattermost) {
+    /// api.event_filter()
+    ///     .update(|filter| filter.channels(["new_channel_id"]));
+    /// # }
+    /// ```
+    pub fn update(&self, change: impl FnOnce(EventFilter) -> EventFilter) {
+        let mut filter = self.filter.write().unwrap();
+        *filter = change(std::mem::take(&mut *filter));
+    }
+
+    /// If the event passes the current filter.
+    pub fn matches(&self, event: &WebsocketEvent) -> bool {
+        self.filter.read().unwrap().matches(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventFilter, EventFilterHandle};
+    use crate::socket::WebsocketEvent;
+
+    fn event(name: &str, channel_id: &str) -> WebsocketEvent {
+        serde_json::from_str(&format!(
+            r#"{{"event":"{name}","data":{{"status":"online","user_id":"u1"}},
+            "broadcast":{{"omit_users":null,"user_id":"u1","channel_id":"{channel_id}","team_id":"t1"}},"seq":1}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn events_must_match_every_criterion() {
+        let filter = EventFilter::new()
+            .event_types(["status_change"])
+            .channels(["c1"])
+            .teams(["t1"]);
+
+        assert!(filter.matches(&event("status_change", "c1")));
+        assert!(!filter.matches(&event("status_change", "c2")));
+        assert!(!filter.matches(&event("some_future_event", "c1")));
+        assert!(EventFilter::new().matches(&event("some_future_event", "c2")));
+
+        let filter = filter.predicate(|broadcast| broadcast.user_id.as_deref() == Some("u2"));
+        assert!(!filter.matches(&event("status_change", "c1")));
+    }
+
+    #[test]
+    fn handles_update_the_shared_filter() {
+        let handle = EventFilterHandle::default();
+        let other = handle.clone();
+
+        other.update(|filter| filter.users(["u2"]));
+        assert!(!handle.matches(&event("status_change", "c1")));
+
+        other.set(EventFilter::new());
+        assert!(handle.matches(&event("status_change", "c1")));
+    }
+}
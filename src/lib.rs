@@ -20,6 +20,22 @@
 //! # }
 //! ```
 //!
+//! # Features
+//!
+//! The websocket runs on Tokio by default. To use async-std instead, disable
+//! the default features and enable `async-std-native-tls` or `async-std-rustls`
+//! (or `async-std-runtime` for unencrypted connections only).
+//!
+//! - `native-tls` (default): Tokio, with TLS from the platform
+//! - `rustls`: Tokio, with rustls and the webpki roots
+//! - `rustls-native-certs`: Tokio, with rustls and the platform roots
+//! - `tokio-runtime`: Tokio, without TLS for the websocket
+//! - `ws-keep-alive` (default): keep websocket connections alive with pings
+//!
+//! Without a Tokio or async-std feature, only the REST API is available:
+//! the websocket, event handling and bot modules are left out, along with
+//! the client methods waiting on timers or writing files.
+//!
 //! [their docs]: https://api.mattermost.com
 //! [`AuthenticationData`]: struct.AuthenticationData.html
 //! [`Mattermost::new`]: struct.Mattermost.html
@@ -29,9 +45,23 @@
 #![warn(missing_docs)]
 
 pub mod client;
+#[cfg(has_runtime)]
+pub mod commands;
+#[cfg(has_runtime)]
+pub mod conversation;
+#[cfg(has_runtime)]
+pub mod dispatch;
 pub mod errors;
+#[cfg(has_runtime)]
+pub mod filter;
 pub mod models;
 pub mod prelude;
+#[cfg(has_runtime)]
+pub mod reconnect;
+#[cfg(has_runtime)]
+mod runtime;
+#[cfg(has_runtime)]
 pub mod socket;
-/// Re-exported since websocket events have untyped data for now
+pub mod transport;
+/// Re-exported since some websocket event and model data is untyped
 pub use serde_json::Value;
@@ -2,17 +2,21 @@
 
 #![allow(missing_docs)]
 
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, io, pin::Pin};
 
+use bytes::Bytes;
+use futures_util::{io::AsyncRead, Stream, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::errors::ApiError;
+
 /// Error struct from Mattermost.
 ///
 /// See [here] for more information.
 ///
 /// [here]: https://api.mattermost.com/#tag/errors
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MattermostError {
     pub id: String,
     pub message: String,
@@ -22,7 +26,7 @@ pub struct MattermostError {
     pub detailed_error: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Post {
     pub id: String,
     pub create_at: usize,
@@ -40,10 +44,11 @@ pub struct Post {
     pub hashtag: Option<String>,
     pub file_ids: Option<Vec<String>>,
     pub pending_post_id: String,
+    #[serde(default)]
     pub metadata: Metadata,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct Metadata {
     pub embeds: Option<Vec<Embed>>,
     pub emojis: Option<Vec<Emoji>>,
@@ -54,7 +59,7 @@ pub struct Metadata {
     pub acknowledgements: Option<Vec<Acknowledgement>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Embed {
     #[serde(rename = "type")]
     pub embed_type: String,
@@ -62,7 +67,7 @@ pub struct Embed {
     pub data: Option<Value>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Emoji {
     pub id: String,
     pub creator_id: String,
@@ -72,11 +77,12 @@ pub struct Emoji {
     pub delete_at: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileMetadata {
     pub id: String,
     pub user_id: String,
     pub post_id: String,
+    pub channel_id: Option<String>,
     pub create_at: usize,
     pub update_at: usize,
     pub delete_at: usize,
@@ -89,7 +95,7 @@ pub struct FileMetadata {
     pub has_preview_image: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Reaction {
     pub user_id: String,
     pub post_id: String,
@@ -97,7 +103,7 @@ pub struct Reaction {
     pub create_at: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged, rename_all = "lowercase")]
 pub enum PriorityLevel {
     #[serde(rename = "")]
@@ -106,13 +112,13 @@ pub enum PriorityLevel {
     Important,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Priority {
     pub priority: PriorityLevel,
     pub requested_ack: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Acknowledgement {
     pub user_id: String,
     pub post_id: String,
@@ -120,7 +126,7 @@ pub struct Acknowledgement {
 }
 
 /// Thread
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Thread {
     pub order: Vec<String>,
     pub posts: HashMap<String, Post>,
@@ -130,7 +136,7 @@ pub struct Thread {
 }
 
 /// Response struct from /teams/name/{name}
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TeamInformation {
     pub id: String,
     pub create_at: i64,
@@ -148,14 +154,14 @@ pub struct TeamInformation {
     pub policy_id: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum BoolishFlag {
     True,
     False,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum NotificationFlag {
     All,
@@ -168,7 +174,7 @@ pub struct CreatePostMetadata {
     pub priority: Option<Priority>,
 }
 
-#[derive(Default, Debug, Deserialize)]
+#[derive(Default, Debug, Clone, Deserialize)]
 pub struct DeleteReactionResponse {
     pub status: String,
 }
@@ -201,7 +207,7 @@ pub struct PatchPost {
     pub file_ids: Option<Vec<String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserNotifyProps {
     pub email: Option<BoolishFlag>,
     pub push: Option<NotificationFlag>,
@@ -212,7 +218,7 @@ pub struct UserNotifyProps {
     pub first_name: Option<BoolishFlag>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Timezone {
     pub use_automatic_timezone: String,
@@ -220,19 +226,25 @@ pub struct Timezone {
     pub automatic_timezone: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: String,
     pub create_at: i64,
     pub update_at: i64,
     pub delete_at: i64,
     pub username: String,
+    #[serde(default)]
     pub first_name: String,
+    #[serde(default)]
     pub last_name: String,
+    #[serde(default)]
     pub email: String,
     pub email_verified: Option<String>,
+    #[serde(default)]
     pub auth_service: String,
+    #[serde(default)]
     pub roles: String,
+    #[serde(default)]
     pub locale: String,
     pub notify_props: Option<UserNotifyProps>,
     pub props: Option<Value>,
@@ -240,19 +252,165 @@ pub struct User {
     pub last_picture_update: Option<i64>,
     pub failed_attemps: Option<i64>,
     pub mfa_active: Option<bool>,
+    #[serde(default)]
     pub timezone: Timezone,
     pub terms_of_service_id: Option<String>,
     pub terms_of_service_create_at: Option<i64>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileUploadResponse {
     pub file_infos: Vec<FileMetadata>,
     pub client_ids: Vec<String>,
 }
 
+/// Response struct from /files/{file_id}/link
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileLink {
+    pub link: String,
+}
+
+/// Request body for file searches.
+#[derive(Debug, Serialize)]
+pub struct FileSearch {
+    /// Search terms, supporting the same modifiers as the search box
+    pub terms: String,
+    pub is_or_search: bool,
+    pub time_zone_offset: Option<i64>,
+    pub include_deleted_channels: Option<bool>,
+    pub page: Option<u64>,
+    pub per_page: Option<u64>,
+}
+
+impl FileSearch {
+    /// Search for files matching `terms`.
+    pub fn new(terms: impl Into<String>) -> Self {
+        Self {
+            terms: terms.into(),
+            is_or_search: false,
+            time_zone_offset: None,
+            include_deleted_channels: None,
+            page: None,
+            per_page: None,
+        }
+    }
+
+    /// Only match files posted in the channel with the given name.
+    pub fn in_channel(mut self, channel_name: &str) -> Self {
+        self.terms = format!("{} in:{channel_name}", self.terms)
+            .trim_start()
+            .to_string();
+        self
+    }
+}
+
+/// Response struct from file searches.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileInfoList {
+    /// File ids, in order of relevance
+    pub order: Vec<String>,
+    pub file_infos: HashMap<String, FileMetadata>,
+    pub next_file_id: String,
+    pub prev_file_id: String,
+}
+
+impl FileInfoList {
+    /// Iterate over the files in order of relevance.
+    pub fn files(&self) -> impl Iterator<Item = &FileMetadata> {
+        self.order.iter().filter_map(|id| self.file_infos.get(id))
+    }
+}
+
+/// An upload session, used to upload a file in several requests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadSession {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub upload_type: String,
+    pub create_at: i64,
+    pub user_id: String,
+    pub channel_id: Option<String>,
+    pub filename: String,
+    pub file_size: u64,
+    /// Number of bytes received by the server so far
+    pub file_offset: u64,
+}
+
+/// Request body for POST /uploads
+#[derive(Default, Debug, Serialize)]
+pub struct CreateUploadSession {
+    pub channel_id: String,
+    pub filename: String,
+    pub file_size: u64,
+}
+
+/// Stream of byte chunks from a response body.
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, ApiError>> + Send>>;
+
+/// A file being downloaded with `Mattermost::get_file_stream`.
+pub struct FileDownload {
+    /// Size of the file in bytes, if reported by the server
+    pub content_length: Option<u64>,
+    pub content_type: Option<String>,
+    /// Name of the file, from the `Content-Disposition` header
+    pub filename: Option<String>,
+    /// Chunks of the file content, in order
+    pub stream: ByteStream,
+}
+
+impl fmt::Debug for FileDownload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileDownload")
+            .field("content_length", &self.content_length)
+            .field("content_type", &self.content_type)
+            .field("filename", &self.filename)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FileDownload {
+    /// Read the file content through an `AsyncRead` instead of a stream of chunks.
+    pub fn into_async_read(self) -> impl AsyncRead + Send + Unpin {
+        self.stream.map_err(io::Error::other).into_async_read()
+    }
+}
+
+/// A file to upload with `Mattermost::upload_files`.
+#[derive(Default, Debug)]
+pub struct UploadFile {
+    pub filename: String,
+    pub content: Vec<u8>,
+    /// MIME type of the content, detected by the server if not set
+    pub mime_type: Option<String>,
+    /// Id chosen by the client to match the file with its upload result
+    pub client_id: Option<String>,
+}
+
+impl UploadFile {
+    /// Create a file to upload from its name and content.
+    pub fn new(filename: impl Into<String>, content: Vec<u8>) -> Self {
+        Self {
+            filename: filename.into(),
+            content,
+            ..Default::default()
+        }
+    }
+
+    /// Set the MIME type of the content.
+    pub fn with_mime_type(mut self, mime_type: impl Into<String>) -> Self {
+        self.mime_type = Some(mime_type.into());
+        self
+    }
+
+    /// Set the id used to match the file with its upload result.
+    pub fn with_client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.client_id = Some(client_id.into());
+        self
+    }
+}
+
 /// Response struct from /users/{user_id}/teams/unread
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TeamsUnreadInformation {
     pub teams_id: String,
     pub msg_count: u64,
@@ -260,7 +418,622 @@ pub struct TeamsUnreadInformation {
 }
 
 /// Information about a single channel on the instance.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChannelInformation {
-    //
+    pub id: String,
+    pub create_at: i64,
+    pub update_at: i64,
+    pub delete_at: i64,
+    pub team_id: String,
+    #[serde(rename = "type")]
+    pub channel_type: String,
+    pub display_name: String,
+    pub name: String,
+    pub header: String,
+    pub purpose: String,
+    pub last_post_at: i64,
+    pub total_msg_count: i64,
+    pub extra_update_at: Option<i64>,
+    pub creator_id: String,
+    pub scheme_id: Option<String>,
+    pub group_constrained: Option<bool>,
+    pub shared: Option<bool>,
+    pub policy_id: Option<String>,
+}
+
+/// A user's membership of a channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelMember {
+    pub channel_id: String,
+    pub user_id: String,
+    pub roles: String,
+    pub last_viewed_at: i64,
+    pub msg_count: i64,
+    pub mention_count: i64,
+    pub msg_count_root: Option<i64>,
+    pub mention_count_root: Option<i64>,
+    pub urgent_mention_count: Option<i64>,
+    pub notify_props: Option<HashMap<String, String>>,
+    pub last_update_at: i64,
+    pub scheme_guest: Option<bool>,
+    pub scheme_user: Option<bool>,
+    pub scheme_admin: Option<bool>,
+    pub explicit_roles: Option<String>,
+}
+
+/// A user's membership of a team.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TeamMember {
+    pub team_id: String,
+    pub user_id: String,
+    pub roles: String,
+    pub delete_at: i64,
+    pub scheme_guest: Option<bool>,
+    pub scheme_user: Option<bool>,
+    pub scheme_admin: Option<bool>,
+    pub explicit_roles: Option<String>,
+}
+
+/// A single user preference.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Preference {
+    pub user_id: String,
+    pub category: String,
+    pub name: String,
+    pub value: String,
+}
+
+/// A role and the permissions it grants.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Role {
+    pub id: String,
+    pub name: String,
+    pub display_name: String,
+    pub description: String,
+    pub create_at: i64,
+    pub update_at: i64,
+    pub delete_at: i64,
+    pub permissions: Vec<String>,
+    pub scheme_managed: bool,
+    pub built_in: bool,
+}
+
+/// Request to open an interactive dialog in a client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenDialog {
+    pub trigger_id: String,
+    pub url: String,
+    pub dialog: Value,
+}
+
+/// A thread followed by a user, as in the thread_updated websocket event payload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserThread {
+    pub id: String,
+    pub reply_count: i64,
+    pub last_reply_at: i64,
+    pub last_viewed_at: i64,
+    pub participants: Option<Vec<Value>>,
+    pub post: Option<Post>,
+    pub unread_replies: i64,
+    pub unread_mentions: i64,
+    pub is_urgent: Option<bool>,
+}
+
+/// Generic `{"status": "OK"}` response returned by many endpoints.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct StatusResponse {
+    pub status: String,
+}
+
+/// A user group, either custom or synced from LDAP.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Group {
+    pub id: String,
+    pub name: Option<String>,
+    pub display_name: String,
+    pub description: String,
+    pub source: String,
+    pub remote_id: Option<String>,
+    pub create_at: i64,
+    pub update_at: i64,
+    pub delete_at: i64,
+    pub has_syncables: Option<bool>,
+    pub member_count: Option<i64>,
+    pub allow_reference: bool,
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct CreateGroupData {
+    pub name: String,
+    pub display_name: String,
+    pub source: String,
+    pub allow_reference: bool,
+}
+
+/// Request body for POST /groups
+#[derive(Default, Debug, Serialize)]
+pub struct CreateGroup {
+    pub group: CreateGroupData,
+    pub user_ids: Vec<String>,
+}
+
+impl CreateGroup {
+    /// Create a custom group, referenceable by `@name`, with the given members.
+    pub fn custom(
+        name: impl Into<String>,
+        display_name: impl Into<String>,
+        user_ids: Vec<String>,
+    ) -> Self {
+        Self {
+            group: CreateGroupData {
+                name: name.into(),
+                display_name: display_name.into(),
+                source: "custom".to_string(),
+                allow_reference: true,
+            },
+            user_ids,
+        }
+    }
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct PatchGroup {
+    pub name: Option<String>,
+    pub display_name: Option<String>,
+    pub description: Option<String>,
+    pub allow_reference: Option<bool>,
+}
+
+/// Response struct from /groups/{group_id}/members
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupMembers {
+    pub members: Vec<User>,
+    pub total_member_count: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupMember {
+    pub group_id: String,
+    pub user_id: String,
+    pub create_at: i64,
+    pub delete_at: i64,
+}
+
+/// Request body for adding or removing group members.
+#[derive(Default, Debug, Serialize)]
+pub struct GroupMemberIds {
+    pub user_ids: Vec<String>,
+}
+
+/// Response struct from /teams/{team_id}/groups and /channels/{channel_id}/groups
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupsWithCount {
+    pub groups: Vec<Group>,
+    pub total_group_count: i64,
+}
+
+/// Kind of object a group can be linked to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupSyncableType {
+    Team,
+    Channel,
+}
+
+impl GroupSyncableType {
+    /// Path segment used by the API for this syncable type.
+    pub fn as_path(&self) -> &'static str {
+        match self {
+            Self::Team => "teams",
+            Self::Channel => "channels",
+        }
+    }
+}
+
+/// A link between a group and a team or channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupSyncable {
+    pub group_id: String,
+    pub team_id: Option<String>,
+    pub channel_id: Option<String>,
+    pub auto_add: bool,
+    pub scheme_admin: Option<bool>,
+    pub create_at: i64,
+    pub update_at: i64,
+    pub delete_at: i64,
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct LinkGroupSyncable {
+    pub auto_add: bool,
+}
+
+/// Response struct from /system/ping
+///
+/// Fields other than `status` are only present when the
+/// full server status was requested.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SystemStatus {
+    pub status: String,
+    pub database_status: Option<String>,
+    pub filestore_status: Option<String>,
+    #[serde(rename = "ActiveSearchBackend")]
+    pub active_search_backend: Option<String>,
+    #[serde(rename = "CanReceiveNotifications")]
+    pub can_receive_notifications: Option<String>,
+    #[serde(rename = "AndroidLatestVersion")]
+    pub android_latest_version: Option<String>,
+    #[serde(rename = "AndroidMinVersion")]
+    pub android_min_version: Option<String>,
+    #[serde(rename = "IosLatestVersion")]
+    pub ios_latest_version: Option<String>,
+    #[serde(rename = "IosMinVersion")]
+    pub ios_min_version: Option<String>,
+    /// Any other status information reported by the server
+    #[serde(flatten)]
+    pub other: HashMap<String, Value>,
+}
+
+/// A product notice from /system/notices/{team_id}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Notice {
+    pub id: String,
+    pub title: String,
+    pub description: String,
+    pub image: Option<String>,
+    pub action: Option<String>,
+    pub action_param: Option<String>,
+    pub action_text: Option<String>,
+    pub sys_admin_only: bool,
+    pub team_admin_only: bool,
+}
+
+/// Type of a background server job.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobType {
+    DataRetention,
+    MessageExport,
+    ElasticsearchPostIndexing,
+    ElasticsearchPostAggregation,
+    BlevePostIndexing,
+    LdapSync,
+    Migrations,
+    Plugins,
+    ExpiryNotify,
+    ProductNotices,
+    ActiveUsers,
+    ImportProcess,
+    ImportDelete,
+    ExportProcess,
+    ExportDelete,
+    ExtractContent,
+    /// Represents an unspecified job type
+    #[serde(untagged)]
+    Other(String),
+}
+
+impl JobType {
+    /// Name used by the API for this job type.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::DataRetention => "data_retention",
+            Self::MessageExport => "message_export",
+            Self::ElasticsearchPostIndexing => "elasticsearch_post_indexing",
+            Self::ElasticsearchPostAggregation => "elasticsearch_post_aggregation",
+            Self::BlevePostIndexing => "bleve_post_indexing",
+            Self::LdapSync => "ldap_sync",
+            Self::Migrations => "migrations",
+            Self::Plugins => "plugins",
+            Self::ExpiryNotify => "expiry_notify",
+            Self::ProductNotices => "product_notices",
+            Self::ActiveUsers => "active_users",
+            Self::ImportProcess => "import_process",
+            Self::ImportDelete => "import_delete",
+            Self::ExportProcess => "export_process",
+            Self::ExportDelete => "export_delete",
+            Self::ExtractContent => "extract_content",
+            Self::Other(name) => name,
+        }
+    }
+}
+
+/// Status of a background server job.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    InProgress,
+    Success,
+    Error,
+    CancelRequested,
+    Canceled,
+    Warning,
+    /// Represents an unspecified job status
+    #[serde(untagged)]
+    Other(String),
+}
+
+impl JobStatus {
+    /// If the job has stopped running and its status will not change anymore.
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            Self::Success | Self::Error | Self::Canceled | Self::Warning
+        )
+    }
+}
+
+/// A background server job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub job_type: JobType,
+    pub priority: Option<i64>,
+    pub create_at: i64,
+    pub start_at: i64,
+    pub last_activity_at: i64,
+    pub status: JobStatus,
+    pub progress: i64,
+    pub data: Option<HashMap<String, String>>,
+}
+
+/// Request body for POST /jobs
+#[derive(Debug, Serialize)]
+pub struct CreateJob {
+    #[serde(rename = "type")]
+    pub job_type: JobType,
+    pub data: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginManifestServer {
+    pub executables: Option<HashMap<String, String>>,
+    pub executable: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginManifestWebapp {
+    pub bundle_path: String,
+}
+
+/// Manifest describing a plugin.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginManifest {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub homepage_url: Option<String>,
+    pub support_url: Option<String>,
+    pub release_notes_url: Option<String>,
+    pub icon_path: Option<String>,
+    pub version: String,
+    pub min_server_version: Option<String>,
+    pub server: Option<PluginManifestServer>,
+    pub webapp: Option<PluginManifestWebapp>,
+    pub settings_schema: Option<Value>,
+    pub props: Option<Value>,
+}
+
+/// Response struct from /plugins
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Plugins {
+    pub active: Vec<PluginManifest>,
+    pub inactive: Vec<PluginManifest>,
+}
+
+/// Running state of a plugin on a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "i64", into = "i64")]
+pub enum PluginState {
+    NotRunning,
+    Starting,
+    Running,
+    FailedToStart,
+    FailedToStayRunning,
+    Stopping,
+    /// Represents an unspecified plugin state
+    Other(i64),
+}
+
+impl From<i64> for PluginState {
+    fn from(value: i64) -> Self {
+        match value {
+            0 => Self::NotRunning,
+            1 => Self::Starting,
+            2 => Self::Running,
+            3 => Self::FailedToStart,
+            4 => Self::FailedToStayRunning,
+            5 => Self::Stopping,
+            other => Self::Other(other),
+        }
+    }
+}
+
+impl From<PluginState> for i64 {
+    fn from(value: PluginState) -> Self {
+        match value {
+            PluginState::NotRunning => 0,
+            PluginState::Starting => 1,
+            PluginState::Running => 2,
+            PluginState::FailedToStart => 3,
+            PluginState::FailedToStayRunning => 4,
+            PluginState::Stopping => 5,
+            PluginState::Other(other) => other,
+        }
+    }
+}
+
+/// Status of a plugin on a single server of the cluster.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginStatus {
+    pub plugin_id: String,
+    pub cluster_id: String,
+    pub plugin_path: String,
+    pub state: PluginState,
+    pub error: Option<String>,
+    pub name: String,
+    pub description: String,
+    pub version: String,
+}
+
+/// A plugin listed on the marketplace.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarketplacePlugin {
+    pub homepage_url: Option<String>,
+    pub icon_data: Option<String>,
+    pub download_url: Option<String>,
+    pub release_notes_url: Option<String>,
+    pub labels: Option<Vec<Value>>,
+    pub hosting: Option<String>,
+    pub author_type: Option<String>,
+    pub release_stage: Option<String>,
+    pub enterprise: Option<bool>,
+    pub signature: Option<String>,
+    pub manifest: PluginManifest,
+    pub installed_version: Option<String>,
+}
+
+/// Request body for POST /plugins/marketplace
+#[derive(Default, Debug, Serialize)]
+pub struct InstallMarketplacePlugin {
+    pub id: String,
+    pub version: Option<String>,
+}
+
+/// Response struct from /data_retention/policy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GlobalRetentionPolicy {
+    pub message_deletion_enabled: bool,
+    pub file_deletion_enabled: bool,
+    pub message_retention_cutoff: i64,
+    pub file_retention_cutoff: i64,
+}
+
+/// A granular data retention policy, applied to specific teams and channels.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub id: String,
+    pub display_name: String,
+    /// Number of days posts are kept, or -1 to keep them forever
+    pub post_duration: i64,
+    pub team_count: Option<i64>,
+    pub channel_count: Option<i64>,
+}
+
+/// Response struct from /data_retention/policies
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionPolicies {
+    pub policies: Vec<RetentionPolicy>,
+    pub total_count: i64,
+}
+
+/// Response struct from /data_retention/policies_count
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionPolicyCount {
+    pub total_count: i64,
+}
+
+/// Request body for POST /data_retention/policies
+#[derive(Default, Debug, Serialize)]
+pub struct CreateRetentionPolicy {
+    pub display_name: String,
+    pub post_duration: i64,
+    pub team_ids: Vec<String>,
+    pub channel_ids: Vec<String>,
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct PatchRetentionPolicy {
+    pub display_name: Option<String>,
+    pub post_duration: Option<i64>,
+    pub team_ids: Option<Vec<String>>,
+    pub channel_ids: Option<Vec<String>>,
+}
+
+/// Response struct from /data_retention/policies/{policy_id}/teams
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionPolicyTeams {
+    pub teams: Vec<TeamInformation>,
+    pub total_count: i64,
+}
+
+/// Response struct from /data_retention/policies/{policy_id}/channels
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionPolicyChannels {
+    pub channels: Vec<ChannelInformation>,
+    pub total_count: i64,
+}
+
+/// A compliance report.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ComplianceReport {
+    pub id: String,
+    pub create_at: i64,
+    pub user_id: String,
+    pub status: String,
+    pub count: i64,
+    pub desc: String,
+    #[serde(rename = "type")]
+    pub report_type: String,
+    pub start_at: i64,
+    pub end_at: i64,
+    pub keywords: String,
+    pub emails: String,
+}
+
+/// Request body for POST /compliance/reports
+#[derive(Default, Debug, Serialize)]
+pub struct CreateComplianceReport {
+    pub desc: String,
+    pub start_at: i64,
+    pub end_at: i64,
+    /// Space-separated keywords to filter posts by
+    pub keywords: Option<String>,
+    /// Comma-separated emails of the users to filter posts by
+    pub emails: Option<String>,
+}
+
+/// The `ServiceSettings` section of the server configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ServiceSettings {
+    #[serde(rename = "SiteURL")]
+    pub site_url: Option<String>,
+    #[serde(rename = "WebsocketURL")]
+    pub websocket_url: Option<String>,
+    pub enable_user_access_tokens: Option<bool>,
+    pub enable_bot_account_creation: Option<bool>,
+    /// Any other setting of the section
+    #[serde(flatten)]
+    pub other: HashMap<String, Value>,
+}
+
+/// The `FileSettings` section of the server configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct FileSettings {
+    pub enable_file_attachments: Option<bool>,
+    /// If `Mattermost::get_file_link` can be used
+    pub enable_public_link: Option<bool>,
+    /// Largest file that can be uploaded, in bytes
+    pub max_file_size: Option<i64>,
+    /// Any other setting of the section
+    #[serde(flatten)]
+    pub other: HashMap<String, Value>,
+}
+
+/// The `MessageExportSettings` section of the server configuration.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct MessageExportSettings {
+    pub enable_export: Option<bool>,
+    pub export_format: Option<String>,
+    pub daily_run_time: Option<String>,
+    pub export_from_timestamp: Option<i64>,
+    pub batch_size: Option<i64>,
+    pub download_export_results: Option<bool>,
+    pub global_relay_settings: Option<Value>,
 }
@@ -1,6 +1,21 @@
 //! Module for easy imports.
 
 pub use crate::client::{AuthenticationData, Mattermost};
+#[cfg(has_runtime)]
+pub use crate::commands::{Command, CommandRouter};
+#[cfg(has_runtime)]
+pub use crate::conversation::{ConversationHandler, ConversationManager};
+#[cfg(has_runtime)]
+pub use crate::dispatch::HandlerSet;
 pub use crate::errors::ApiError;
+#[cfg(has_runtime)]
+pub use crate::filter::EventFilter;
 pub use crate::models::MattermostError;
-pub use crate::socket::{WebsocketEvent, WebsocketHandler};
+#[cfg(has_runtime)]
+pub use crate::reconnect::{BackoffPolicy, ConnectionState, WebsocketSupervisor};
+#[cfg(has_runtime)]
+pub use crate::socket::{
+    ConnectionInfo, DisconnectReason, MissedEvents, WebsocketEvent, WebsocketEventStream,
+    WebsocketHandler, WebsocketSender,
+};
+pub use crate::transport::TransportConfig;
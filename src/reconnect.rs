@@ -0,0 +1,263 @@
+//! Supervision of a websocket connection that reconnects on errors.
+
+use crate::{
+    client::Mattermost, errors::ApiError, runtime, socket::WebsocketHandler,
+    socket::WebsocketSender,
+};
+use futures_util::future::{self, RemoteHandle};
+use log::{debug, error, warn};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// How long `WebsocketSupervisor::shutdown` waits for the server to acknowledge the close.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Policy for the delays between reconnection attempts.
+///
+/// The delay starts at `initial_delay` and is multiplied by
+/// `multiplier` after each failed attempt, up to `max_delay`.
+/// The attempt count is reset once a connection is made.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackoffPolicy {
+    /// Delay before the first reconnection attempt
+    pub initial_delay: Duration,
+    /// Upper bound for the delay
+    pub max_delay: Duration,
+    /// Factor applied to the delay after each failed attempt
+    pub multiplier: f64,
+    /// Number of consecutive failed attempts after which to give up, or `None` to retry forever
+    pub max_retries: Option<u32>,
+}
+
+impl Default for BackoffPolicy {
+    /// Starts at 1 second, doubling up to 1 minute, retrying forever.
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            max_retries: None,
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// Wait the same `delay` before every attempt.
+    pub fn fixed(delay: Duration) -> Self {
+        Self {
+            initial_delay: delay,
+            max_delay: delay,
+            multiplier: 1.0,
+            max_retries: None,
+        }
+    }
+
+    /// Give up after `max_retries` consecutive failed attempts.
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// Delay before reconnecting after `attempt` consecutive failures, starting at 0.
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        let factor = self
+            .multiplier
+            .max(1.0)
+            .powi(attempt.min(i32::MAX as u32) as i32);
+        self.initial_delay
+            .mul_f64(factor.min(u32::MAX as f64))
+            .min(self.max_delay)
+    }
+}
+
+/// State of a supervised websocket connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// A connection is being opened
+    Connecting {
+        /// Number of consecutive failed attempts before this one
+        attempt: u32,
+    },
+    /// The connection is open and events are being received
+    Connected,
+    /// Waiting before the next reconnection attempt
+    BackingOff {
+        /// Number of consecutive failed attempts so far
+        attempt: u32,
+        /// Time to wait before reconnecting
+        delay: Duration,
+    },
+    /// The supervisor was shut down or gave up reconnecting
+    Stopped,
+}
+
+/// Supervisor of a websocket connection that reconnects on errors.
+///
+/// Returned by `Mattermost::with_reconnection`.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use mattermost_api::{prelude::*, reconnect::BackoffPolicy};
+/// # struct Handler;
+/// # impl WebsocketHandler for Handler {}
+/// # async fn run(api: Mattermost) {
+/// let supervisor = api
+///     .with_reconnection_backoff(BackoffPolicy::default().with_max_retries(10))
+///     .with_reconnection(Handler);
+/// // ...
+/// println!("{:?}", supervisor.state());
+/// supervisor.shutdown().await;
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct WebsocketSupervisor {
+    state: watch::Receiver<ConnectionState>,
+    shutdown: watch::Sender<bool>,
+    sender: WebsocketSender,
+    /// Handle to the supervisor task, taken when waiting for it
+    handle: Option<RemoteHandle<()>>,
+}
+
+impl Drop for WebsocketSupervisor {
+    /// Keep the supervisor running in the background.
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.forget();
+        }
+    }
+}
+
+impl WebsocketSupervisor {
+    /// Start supervising connections of `api`, dispatching events to `handler`.
+    pub(crate) fn spawn<H: WebsocketHandler + 'static>(
+        api: Mattermost,
+        handler: H,
+        policy: BackoffPolicy,
+    ) -> Self {
+        let (state_tx, state) = watch::channel(ConnectionState::Connecting { attempt: 0 });
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let sender = api.websocket_sender();
+        let handle =
+            runtime::spawn_with_handle(supervise(api, handler, policy, state_tx, shutdown_rx));
+        Self {
+            state,
+            shutdown,
+            sender,
+            handle: Some(handle),
+        }
+    }
+
+    /// Current state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.state.borrow().clone()
+    }
+
+    /// Receiver notified of every change of the connection state.
+    pub fn watch_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.clone()
+    }
+
+    /// If a connection is currently open.
+    pub fn is_connected(&self) -> bool {
+        *self.state.borrow() == ConnectionState::Connected
+    }
+
+    /// Handle for sending actions over the supervised connection.
+    pub fn sender(&self) -> WebsocketSender {
+        self.sender.clone()
+    }
+
+    /// Wait until the supervisor stops, after giving up reconnecting.
+    pub async fn join(mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.await;
+        }
+    }
+
+    /// Stop reconnecting, and close the open connection gracefully.
+    ///
+    /// If the server does not acknowledge the close in time,
+    /// the connection is dropped.
+    pub async fn shutdown(mut self) {
+        debug!("Shutting down websocket supervisor");
+        let _ = self.shutdown.send(true);
+        match self.sender.close() {
+            Ok(()) | Err(ApiError::WebsocketNotConnected) => {}
+            Err(err) => warn!("Could not close websocket connection: {err}"),
+        }
+        let Some(handle) = self.handle.take() else {
+            return;
+        };
+        // dropping the handle on timeout cancels the task
+        if runtime::timeout(SHUTDOWN_TIMEOUT, handle).await.is_err() {
+            warn!("Websocket connection did not close in time, dropping it");
+        }
+    }
+}
+
+/// Reconnection loop run by the supervisor task.
+async fn supervise<H: WebsocketHandler + 'static>(
+    api: Mattermost,
+    handler: H,
+    policy: BackoffPolicy,
+    state: watch::Sender<ConnectionState>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut attempt = 0;
+    while !*shutdown.borrow() {
+        let _ = state.send(ConnectionState::Connecting { attempt });
+        match api.open_connection().await {
+            Ok(connection) => {
+                attempt = 0;
+                let _ = state.send(ConnectionState::Connected);
+                if *shutdown.borrow() {
+                    let _ = api.websocket_sender().close();
+                }
+                if let Err(err) = api.dispatch_events(connection, &handler).await {
+                    error!("Websocket error: {err:?}");
+                }
+            }
+            Err(err) => {
+                error!("Could not connect to websocket: {err:?}");
+                handler.on_error(&err).await;
+            }
+        }
+        if *shutdown.borrow() {
+            break;
+        }
+
+        if policy.max_retries.is_some_and(|max| attempt >= max) {
+            error!("Giving up reconnecting to websocket after {attempt} attempts");
+            break;
+        }
+        let delay = policy.delay_for(attempt);
+        attempt += 1;
+        debug!("Reconnecting to websocket in {delay:?}");
+        let _ = state.send(ConnectionState::BackingOff { attempt, delay });
+        future::select(
+            Box::pin(runtime::sleep(delay)),
+            Box::pin(shutdown.changed()),
+        )
+        .await;
+    }
+    let _ = state.send(ConnectionState::Stopped);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BackoffPolicy;
+    use std::time::Duration;
+
+    #[test]
+    fn delays_grow_up_to_the_maximum() {
+        let policy = BackoffPolicy::default();
+        let delays: Vec<_> = (0..8).map(|attempt| policy.delay_for(attempt)).collect();
+
+        assert_eq!(
+            delays,
+            [1, 2, 4, 8, 16, 32, 60, 60].map(Duration::from_secs)
+        );
+        assert_eq!(policy.delay_for(u32::MAX), Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,110 @@
+//! Functions depending on the async runtime.
+//!
+//! Tokio is used by default, and async-std with the `async-std-runtime` feature.
+
+use crate::errors::ApiError;
+use futures_util::{
+    future::{self, Either, RemoteHandle},
+    FutureExt,
+};
+use std::{future::Future, time::Duration};
+
+#[cfg(all(feature = "tokio-runtime", not(feature = "async-std-runtime")))]
+mod imp {
+    use std::{future::Future, io, path::Path, time::Duration};
+
+    pub(crate) use async_tungstenite::tokio::{
+        client_async_tls_with_connector_and_config, ConnectStream,
+    };
+    pub(crate) use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    pub(crate) fn spawn<F: Future<Output = ()> + Send + 'static>(future: F) {
+        tokio::spawn(future);
+    }
+
+    pub(crate) async fn sleep(duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+
+    /// File being written to.
+    pub(crate) struct File(tokio::fs::File);
+
+    impl File {
+        pub(crate) async fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+            Ok(Self(tokio::fs::File::create(path).await?))
+        }
+
+        pub(crate) async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+            self.0.write_all(data).await
+        }
+
+        pub(crate) async fn flush(&mut self) -> io::Result<()> {
+            self.0.flush().await
+        }
+    }
+}
+
+#[cfg(feature = "async-std-runtime")]
+mod imp {
+    use std::{future::Future, io, path::Path, time::Duration};
+
+    pub(crate) use async_std::net::TcpStream;
+    pub(crate) use async_tungstenite::async_std::{
+        client_async_tls_with_connector_and_config, ConnectStream,
+    };
+    pub(crate) use futures_util::io::{AsyncReadExt, AsyncWriteExt};
+
+    pub(crate) fn spawn<F: Future<Output = ()> + Send + 'static>(future: F) {
+        async_std::task::spawn(future);
+    }
+
+    pub(crate) async fn sleep(duration: Duration) {
+        async_std::task::sleep(duration).await
+    }
+
+    /// File being written to.
+    pub(crate) struct File(async_std::fs::File);
+
+    impl File {
+        pub(crate) async fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+            Ok(Self(async_std::fs::File::create(path.as_ref()).await?))
+        }
+
+        pub(crate) async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+            self.0.write_all(data).await
+        }
+
+        pub(crate) async fn flush(&mut self) -> io::Result<()> {
+            self.0.flush().await
+        }
+    }
+}
+
+pub(crate) use imp::*;
+
+/// Run `future`, returning `ApiError::Timeout` if it does not complete within `duration`.
+pub(crate) async fn timeout<F: Future>(
+    duration: Duration,
+    future: F,
+) -> Result<F::Output, ApiError> {
+    match future::select(Box::pin(future), Box::pin(sleep(duration))).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(ApiError::Timeout),
+    }
+}
+
+/// Spawn `future` on the runtime, returning a handle to its output.
+///
+/// Dropping the handle cancels the task, unless it is forgotten.
+pub(crate) fn spawn_with_handle<F>(future: F) -> RemoteHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send,
+{
+    let (task, handle) = future.remote_handle();
+    spawn(task);
+    handle
+}
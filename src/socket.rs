@@ -1,13 +1,44 @@
 //! Websocket client and trait for interacting with the websocket API.
 
-use crate::models::{Post, Reaction};
+use crate::errors::ApiError;
+use crate::filter::EventFilterHandle;
+use crate::models::{
+    ChannelInformation, ChannelMember, Emoji, OpenDialog, PluginManifest, PluginStatus, Post,
+    Preference, Reaction, Role, TeamInformation, TeamMember, User, UserThread,
+};
+use crate::runtime::{self, ConnectStream};
 use async_trait::async_trait;
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::{collections::HashMap, sync::Arc};
+use async_tungstenite::{
+    tungstenite::{protocol::CloseFrame, Message},
+    WebSocketStream,
+};
+use futures_util::{
+    future::{ready, RemoteHandle},
+    stream::{self, SplitSink},
+    SinkExt, Stream, StreamExt,
+};
+use log::{debug, error, warn};
+use serde::{
+    de::{self, value::MapDeserializer},
+    ser::SerializeStruct,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fmt,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, oneshot};
 
 /// Websocket event broadcast information
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WebsocketEventBroadcast {
     /// Users who were omitted from receiving the event
     pub omit_users: Option<HashMap<String, bool>>,
@@ -20,7 +51,7 @@ pub struct WebsocketEventBroadcast {
 }
 
 /// Event data from the websocket API
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WebsocketEvent {
     /// Event type and data
     #[serde(flatten)]
@@ -31,6 +62,731 @@ pub struct WebsocketEvent {
     pub seq: usize,
 }
 
+/// Reply from the server to an action sent over the websocket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebsocketReply {
+    /// "OK" or "FAIL"
+    pub status: String,
+    /// Sequence number of the action this is a reply to
+    pub seq_reply: u64,
+    /// Data returned by the action
+    pub data: Option<Value>,
+    /// Error information if the action failed
+    pub error: Option<Value>,
+}
+
+/// Handle for sending actions over the websocket connection.
+///
+/// Get one with `Mattermost::websocket_sender`. Handles stay valid
+/// across reconnections; sending while no connection is open
+/// returns `ApiError::WebsocketNotConnected`.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use mattermost_api::prelude::*;
+/// # async fn run(api: Mattermost) {
+/// let sender = api.websocket_sender();
+/// sender.user_typing("channel_id", None).await.unwrap();
+/// let statuses = sender.get_statuses().await.unwrap();
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct WebsocketSender {
+    inner: Arc<SenderInner>,
+    reply_timeout: Duration,
+}
+
+#[derive(Default)]
+struct SenderInner {
+    next_seq: AtomicU64,
+    next_generation: AtomicU64,
+    /// Generation of the open connection and its queue of outgoing messages
+    outgoing: Mutex<Option<(u64, mpsc::UnboundedSender<Message>)>>,
+    pending: Mutex<HashMap<u64, oneshot::Sender<WebsocketReply>>>,
+}
+
+impl Default for WebsocketSender {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(SenderInner {
+                next_seq: AtomicU64::new(1),
+                ..Default::default()
+            }),
+            reply_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl fmt::Debug for WebsocketSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebsocketSender")
+            .field("connected", &self.is_connected())
+            .field("reply_timeout", &self.reply_timeout)
+            .finish()
+    }
+}
+
+impl WebsocketSender {
+    /// Changes how long to wait for the reply to an action.
+    ///
+    /// The default is 10 seconds.
+    pub fn with_reply_timeout(mut self, timeout: Duration) -> Self {
+        self.reply_timeout = timeout;
+        self
+    }
+
+    /// If a websocket connection is currently open.
+    pub fn is_connected(&self) -> bool {
+        self.inner.outgoing.lock().unwrap().is_some()
+    }
+
+    /// Send an action and wait for the server's reply.
+    ///
+    /// Returns `ApiError::WebsocketActionError` if the server
+    /// replied that the action failed.
+    pub async fn send_action(&self, action: &str, data: Value) -> Result<WebsocketReply, ApiError> {
+        let seq = self.next_seq();
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.inner.pending.lock().unwrap().insert(seq, reply_tx);
+
+        let message = json!({ "seq": seq, "action": action, "data": data });
+        if let Err(err) = self.send(Message::Text(serde_json::to_string(&message)?)) {
+            self.inner.pending.lock().unwrap().remove(&seq);
+            return Err(err);
+        }
+
+        let reply = match runtime::timeout(self.reply_timeout, reply_rx).await {
+            Ok(Ok(reply)) => reply,
+            // the connection closed before the reply arrived
+            Ok(Err(_)) => return Err(ApiError::WebsocketNotConnected),
+            Err(_) => {
+                self.inner.pending.lock().unwrap().remove(&seq);
+                return Err(ApiError::Timeout);
+            }
+        };
+        if reply.status != "OK" {
+            let error = reply
+                .error
+                .as_ref()
+                .and_then(|err| err.get("message"))
+                .and_then(Value::as_str)
+                .map(String::from)
+                .unwrap_or_else(|| format!("{:?}", reply.error));
+            return Err(ApiError::WebsocketActionError(error));
+        }
+        Ok(reply)
+    }
+
+    /// Show the current user as typing in a channel, or in a thread if `parent_id` is set.
+    pub async fn user_typing(
+        &self,
+        channel_id: &str,
+        parent_id: Option<&str>,
+    ) -> Result<(), ApiError> {
+        self.send_action(
+            "user_typing",
+            json!({ "channel_id": channel_id, "parent_id": parent_id.unwrap_or_default() }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Get the statuses of all users the server has statuses for, keyed by user id.
+    pub async fn get_statuses(&self) -> Result<HashMap<String, String>, ApiError> {
+        let reply = self.send_action("get_statuses", json!({})).await?;
+        Ok(serde_json::from_value(reply.data.unwrap_or_default())?)
+    }
+
+    /// Get the statuses of the given users, keyed by user id.
+    pub async fn get_statuses_by_ids(
+        &self,
+        user_ids: &[&str],
+    ) -> Result<HashMap<String, String>, ApiError> {
+        let reply = self
+            .send_action("get_statuses_by_ids", json!({ "user_ids": user_ids }))
+            .await?;
+        Ok(serde_json::from_value(reply.data.unwrap_or_default())?)
+    }
+
+    /// Next sequence number for a message sent to the server.
+    pub(crate) fn next_seq(&self) -> u64 {
+        self.inner.next_seq.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Queue a raw message to be sent over the open connection.
+    pub(crate) fn send(&self, message: Message) -> Result<(), ApiError> {
+        self.inner
+            .outgoing
+            .lock()
+            .unwrap()
+            .as_ref()
+            .ok_or(ApiError::WebsocketNotConnected)?
+            .1
+            .send(message)
+            .map_err(|_| ApiError::WebsocketNotConnected)
+    }
+
+    /// Close the websocket connection.
+    ///
+    /// A close message is sent to the server, and the connection
+    /// ends once the server acknowledges it.
+    pub fn close(&self) -> Result<(), ApiError> {
+        self.send(Message::Close(None))
+    }
+
+    /// Route messages sent through this handle to a new connection.
+    ///
+    /// Returns the connection's generation, to pass to `detach`.
+    pub(crate) fn attach(&self) -> (u64, mpsc::UnboundedReceiver<Message>) {
+        let generation = self.inner.next_generation.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::unbounded_channel();
+        *self.inner.outgoing.lock().unwrap() = Some((generation, tx));
+        (generation, rx)
+    }
+
+    /// Mark the connection as closed, failing actions awaiting a reply.
+    ///
+    /// Does nothing if a newer connection has been attached since.
+    pub(crate) fn detach(&self, generation: u64) {
+        let mut outgoing = self.inner.outgoing.lock().unwrap();
+        if matches!(&*outgoing, Some((current, _)) if *current == generation) {
+            *outgoing = None;
+            self.inner.pending.lock().unwrap().clear();
+        }
+    }
+
+    /// Resolve the action a reply belongs to.
+    pub(crate) fn handle_reply(&self, reply: WebsocketReply) {
+        match self.inner.pending.lock().unwrap().remove(&reply.seq_reply) {
+            Some(tx) => {
+                let _ = tx.send(reply);
+            }
+            None => debug!("No pending action for reply {}. Skipping.", reply.seq_reply),
+        }
+    }
+}
+
+/// Information about events missed while reconnecting.
+#[derive(Debug, Clone)]
+pub struct MissedEvents {
+    /// Id of the connection that could not be resumed
+    pub previous_connection_id: String,
+    /// Id of the new connection
+    pub connection_id: String,
+}
+
+/// Information about an opened connection, from its `Hello` event,
+/// or from the previous connection if it was resumed.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// Id of the connection
+    pub connection_id: String,
+    /// Version of the Mattermost server
+    pub server_version: String,
+    /// If the previous connection was resumed, so no events were missed
+    pub resumed: bool,
+}
+
+/// Reason for a websocket connection ending.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The connection was closed, by either side
+    Closed {
+        /// Close code sent by the server, if any
+        code: Option<u16>,
+        /// Close reason sent by the server, may be empty
+        reason: String,
+    },
+    /// The connection was ended by an error
+    Error(String),
+}
+
+/// State needed to resume a websocket session after reconnecting.
+#[derive(Debug, Default)]
+pub(crate) struct WebsocketSession {
+    /// Id of the last connection, from its `Hello` event
+    pub(crate) connection_id: Option<String>,
+    /// Sequence number of the next event expected from the server
+    pub(crate) next_seq: u64,
+    /// Version of the server, from the last `Hello` event
+    pub(crate) server_version: String,
+}
+
+impl WebsocketSession {
+    /// Check that `seq` is the next sequence number, and record it as received.
+    fn advance(&mut self, seq: u64) -> Result<(), ApiError> {
+        if seq != self.next_seq {
+            error!("Expected websocket event {}, got {seq}", self.next_seq);
+            return Err(ApiError::WebsocketSequenceGap {
+                expected: self.next_seq,
+                received: seq,
+            });
+        }
+        self.next_seq += 1;
+        Ok(())
+    }
+}
+
+/// Inputs to the websocket connection loop.
+enum WebsocketInput {
+    Incoming(Result<Message, async_tungstenite::tungstenite::Error>),
+    Outgoing(Message),
+    #[cfg_attr(not(feature = "ws-keep-alive"), allow(dead_code))]
+    Ping,
+    Closed,
+}
+
+/// Message received on a connection.
+// short-lived, so boxing events is not worth it
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Received {
+    Event(WebsocketEvent),
+    /// Text of a message that could not be parsed, and was skipped
+    Unparsed(String),
+}
+
+/// Outcome of handling a single message.
+#[allow(clippy::large_enum_variant)]
+enum Handled {
+    Event(WebsocketEvent),
+    /// Text of a message that could not be parsed, and its sequence number if it is an event
+    Unparsed(String, Option<u64>),
+}
+
+/// Output of the task driving a connection's socket.
+#[allow(clippy::large_enum_variant)]
+enum IoOutput {
+    Connected(ConnectionInfo),
+    MissedEvents(MissedEvents),
+    Received(Result<Received, ApiError>),
+    /// The connection was closed, with the close frame sent by the server
+    Closed(Option<CloseFrame<'static>>),
+}
+
+/// Socket side of a connection, sending queued actions and resolving their replies.
+///
+/// Runs in its own task, so that handlers awaiting a reply do not stop the socket from being read.
+pub(crate) struct ConnectionIo {
+    sink: SplitSink<WebSocketStream<ConnectStream>, Message>,
+    inputs: Pin<Box<dyn Stream<Item = WebsocketInput> + Send>>,
+    sender: WebsocketSender,
+    generation: u64,
+    session: Arc<Mutex<WebsocketSession>>,
+    output: Option<mpsc::UnboundedSender<IoOutput>>,
+    close_frame: Option<CloseFrame<'static>>,
+    /// When the last message was received from the server
+    last_received: Instant,
+    /// How long the server can stay silent, if keep-alive is enabled
+    pong_timeout: Option<Duration>,
+    /// If no event was received yet, so the next one tells if the session was resumed
+    awaiting_first_event: bool,
+    closed: bool,
+}
+
+impl ConnectionIo {
+    /// Take over an authenticated stream, routing actions from `sender` to it.
+    ///
+    /// `received` are the messages already read from the stream, handled first.
+    /// `session` is updated as events arrive, so that a later connection can resume it.
+    pub(crate) fn new(
+        stream: WebSocketStream<ConnectStream>,
+        received: Vec<Message>,
+        sender: WebsocketSender,
+        session: Arc<Mutex<WebsocketSession>>,
+    ) -> Self {
+        let (sink, stream) = stream.split();
+        let (generation, outgoing) = sender.attach();
+
+        let incoming = stream::iter(received.into_iter().map(Ok))
+            .chain(stream)
+            .map(WebsocketInput::Incoming)
+            .chain(stream::once(ready(WebsocketInput::Closed)));
+        let outgoing = stream::unfold(outgoing, |mut outgoing| async move {
+            let message = outgoing.recv().await?;
+            Some((WebsocketInput::Outgoing(message), outgoing))
+        });
+
+        Self {
+            sink,
+            inputs: Box::pin(stream::select(incoming, outgoing)),
+            sender,
+            generation,
+            session,
+            output: None,
+            close_frame: None,
+            last_received: Instant::now(),
+            pong_timeout: None,
+            awaiting_first_event: true,
+            closed: false,
+        }
+    }
+
+    /// Send ping messages every `interval` to keep the connection alive.
+    ///
+    /// The connection ends with an error if nothing was received from
+    /// the server for `pong_timeout` when a ping is sent.
+    #[cfg(feature = "ws-keep-alive")]
+    pub(crate) fn with_keep_alive(mut self, interval: Duration, pong_timeout: Duration) -> Self {
+        self.pong_timeout = Some(pong_timeout);
+        let inputs = std::mem::replace(&mut self.inputs, Box::pin(stream::empty()));
+        let pings = stream::once(ready(WebsocketInput::Ping)).chain(stream::unfold(
+            (),
+            move |()| async move {
+                runtime::sleep(interval).await;
+                Some((WebsocketInput::Ping, ()))
+            },
+        ));
+        // messages already received are handled before a ping, so that
+        // they count as an answer even if the ping is due at the same time
+        self.inputs = Box::pin(stream::select_with_strategy(inputs, pings, |_: &mut ()| {
+            stream::PollNext::Left
+        }));
+        self
+    }
+
+    /// Handle the socket until the connection closes or `output` is dropped.
+    async fn run(mut self, output: mpsc::UnboundedSender<IoOutput>) {
+        self.output = Some(output);
+        loop {
+            match self.next_received().await {
+                Some(Ok(received)) => {
+                    if !self.emit(IoOutput::Received(Ok(received))) {
+                        break;
+                    }
+                }
+                Some(Err(err)) => {
+                    self.emit(IoOutput::Received(Err(err)));
+                    break;
+                }
+                None => {
+                    let frame = self.close_frame.take();
+                    self.emit(IoOutput::Closed(frame));
+                    break;
+                }
+            }
+        }
+        self.sender.detach(self.generation);
+    }
+
+    /// Wait for the next event or unparsable message, handling replies and outgoing messages meanwhile.
+    ///
+    /// Returns `None` once the connection is closed.
+    async fn next_received(&mut self) -> Option<Result<Received, ApiError>> {
+        loop {
+            match self.inputs.next().await? {
+                WebsocketInput::Incoming(Ok(message)) => match self.handle_message(message) {
+                    Some(Handled::Event(event)) => {
+                        return Some(self.track_sequence(&event).map(|_| Received::Event(event)))
+                    }
+                    Some(Handled::Unparsed(text, seq)) => {
+                        // skipped events still count towards the sequence
+                        let checked = seq.map_or(Ok(()), |seq| self.check_sequence(seq));
+                        return Some(checked.map(|_| Received::Unparsed(text)));
+                    }
+                    None if self.closed => return None,
+                    None => {}
+                },
+                WebsocketInput::Incoming(Err(err)) => {
+                    error!("Error getting websocket message: {err}");
+                    return Some(Err(ApiError::WebsocketError(Box::new(err))));
+                }
+                WebsocketInput::Outgoing(message) => {
+                    if let Err(err) = self.sink.send(message).await {
+                        return Some(Err(ApiError::WebsocketError(Box::new(err))));
+                    }
+                }
+                WebsocketInput::Ping => {
+                    if let Some(timeout) = self.pong_timeout {
+                        if self.last_received.elapsed() > timeout {
+                            error!("No message from the websocket server in {timeout:?}, closing the connection");
+                            return Some(Err(ApiError::WebsocketPongTimeout(timeout)));
+                        }
+                    }
+                    if let Err(err) = self.sink.send(Message::Ping(vec![])).await {
+                        error!("Error sending Ping message through websocket: {err}");
+                        return Some(Err(ApiError::WebsocketError(Box::new(err))));
+                    }
+                }
+                WebsocketInput::Closed => return None,
+            }
+        }
+    }
+
+    /// Check that no events were skipped, and record the event as received.
+    ///
+    /// Returns `ApiError::WebsocketSequenceGap` if events are missing,
+    /// so that reconnecting can resume the session and replay them.
+    fn track_sequence(&mut self, event: &WebsocketEvent) -> Result<(), ApiError> {
+        let seq = event.seq as u64;
+
+        if let WebsocketEventType::Hello {
+            connection_id,
+            server_version,
+        } = &event.event
+        {
+            self.awaiting_first_event = false;
+            let mut session = self.session.lock().unwrap();
+            session.server_version = server_version.clone();
+            let mut resumed = false;
+            let mut missed_events = None;
+            match session.connection_id.replace(connection_id.clone()) {
+                Some(previous) if previous == *connection_id => {
+                    session.advance(seq)?;
+                    debug!("Resumed websocket connection {connection_id}");
+                    resumed = true;
+                }
+                previous => {
+                    if let Some(previous_connection_id) = previous {
+                        warn!("Could not resume websocket connection {previous_connection_id}, events were missed");
+                        missed_events = Some(MissedEvents {
+                            previous_connection_id,
+                            connection_id: connection_id.clone(),
+                        });
+                    }
+                    session.next_seq = seq + 1;
+                }
+            }
+            drop(session);
+            self.emit(IoOutput::Connected(ConnectionInfo {
+                connection_id: connection_id.clone(),
+                server_version: server_version.clone(),
+                resumed,
+            }));
+            if let Some(missed) = missed_events {
+                self.emit(IoOutput::MissedEvents(missed));
+            }
+            return Ok(());
+        }
+        self.check_sequence(seq)
+    }
+
+    /// Pass an item on to the dispatching side, returning `false` if it is gone.
+    fn emit(&self, item: IoOutput) -> bool {
+        self.output
+            .as_ref()
+            .is_some_and(|output| output.send(item).is_ok())
+    }
+
+    /// Check that `seq` is the next sequence number, and record it as received.
+    ///
+    /// The server sends no `Hello` event when resuming a session, so a first
+    /// event continuing the sequence of the previous connection means it was resumed.
+    fn check_sequence(&mut self, seq: u64) -> Result<(), ApiError> {
+        let mut session = self.session.lock().unwrap();
+        session.advance(seq)?;
+        if std::mem::take(&mut self.awaiting_first_event) {
+            if let Some(connection_id) = session.connection_id.clone() {
+                debug!("Resumed websocket connection {connection_id}");
+                let info = ConnectionInfo {
+                    connection_id,
+                    server_version: session.server_version.clone(),
+                    resumed: true,
+                };
+                drop(session);
+                self.emit(IoOutput::Connected(info));
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse a message into an event, resolving it instead if it is a reply.
+    ///
+    /// Returns `None` for messages that are not events. Messages that
+    /// cannot be parsed are returned as is, so that they do not end the connection.
+    fn handle_message(&mut self, message: Message) -> Option<Handled> {
+        self.last_received = Instant::now();
+        match message {
+            Message::Text(text) => {
+                let value: Value = match serde_json::from_str(&text) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        error!("Could not parse websocket message JSON: {err}");
+                        return Some(Handled::Unparsed(text, None));
+                    }
+                };
+
+                if value.get("seq_reply").is_some() {
+                    debug!("Reply text message received. Resolving pending action.");
+
+                    match serde_json::from_value(value) {
+                        Ok(reply) => self.sender.handle_reply(reply),
+                        Err(err) => {
+                            error!("Could not parse websocket reply JSON: {err}");
+                            return Some(Handled::Unparsed(text, None));
+                        }
+                    }
+                    return None;
+                }
+
+                debug!("Non-reply text message received.");
+
+                let seq = value.get("seq").and_then(Value::as_u64);
+                match serde_json::from_value(value) {
+                    Ok(event) => Some(Handled::Event(event)),
+                    Err(err) => {
+                        error!("Could not parse websocket event JSON: {err}");
+                        Some(Handled::Unparsed(text, seq))
+                    }
+                }
+            }
+            Message::Close(frame) => {
+                debug!("Close message received.");
+                self.close_frame = frame.map(CloseFrame::into_owned);
+                self.closed = true;
+                None
+            }
+            message => {
+                debug!("Non-text, non-close message received: {message:#?}");
+                None
+            }
+        }
+    }
+}
+
+/// An open websocket connection, yielding the events received by its socket task.
+pub(crate) struct Connection {
+    output: mpsc::UnboundedReceiver<IoOutput>,
+    /// Socket task, cancelled when the connection is dropped
+    _io: RemoteHandle<()>,
+    sender: WebsocketSender,
+    generation: u64,
+    filter: EventFilterHandle,
+    missed_events: Option<MissedEvents>,
+    connected: Option<ConnectionInfo>,
+    close_frame: Option<CloseFrame<'static>>,
+    closed: bool,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.sender.detach(self.generation);
+    }
+}
+
+impl Connection {
+    /// Start handling the socket of `io` in its own task.
+    ///
+    /// Events not matching `filter` are skipped by `next_event`.
+    pub(crate) fn spawn(io: ConnectionIo, filter: EventFilterHandle) -> Self {
+        let (tx, output) = mpsc::unbounded_channel();
+        let sender = io.sender.clone();
+        let generation = io.generation;
+        Self {
+            output,
+            _io: runtime::spawn_with_handle(io.run(tx)),
+            sender,
+            generation,
+            filter,
+            missed_events: None,
+            connected: None,
+            close_frame: None,
+            closed: false,
+        }
+    }
+
+    /// Take the missed events detected when the last `Hello` event was received, if any.
+    pub(crate) fn take_missed_events(&mut self) -> Option<MissedEvents> {
+        self.missed_events.take()
+    }
+
+    /// Take the information from the last `Hello` event received, if not taken yet.
+    pub(crate) fn take_connected(&mut self) -> Option<ConnectionInfo> {
+        self.connected.take()
+    }
+
+    /// Reason for the connection ending, once `next_event` returned `None`.
+    pub(crate) fn close_reason(&self) -> DisconnectReason {
+        let (code, reason) = match &self.close_frame {
+            Some(frame) => (Some(frame.code.into()), frame.reason.to_string()),
+            None => (None, String::new()),
+        };
+        DisconnectReason::Closed { code, reason }
+    }
+
+    /// Wait for the next event matching the filter, skipping messages that could not be parsed.
+    ///
+    /// Returns `None` once the connection is closed; an error closes it.
+    pub(crate) async fn next_event(&mut self) -> Option<Result<WebsocketEvent, ApiError>> {
+        loop {
+            match self.next_received().await? {
+                Ok(Received::Event(event)) if self.matches_filter(&event) => {
+                    return Some(Ok(event))
+                }
+                Ok(Received::Event(_)) => {}
+                Ok(Received::Unparsed(_)) => {}
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+
+    /// If the event passes the event filter of the client.
+    pub(crate) fn matches_filter(&self, event: &WebsocketEvent) -> bool {
+        let matches = self.filter.matches(event);
+        if !matches {
+            debug!("Websocket event {} filtered out", event.event.name());
+        }
+        matches
+    }
+
+    /// Wait for the next event or unparsable message.
+    ///
+    /// Returns `None` once the connection is closed; an error closes it.
+    pub(crate) async fn next_received(&mut self) -> Option<Result<Received, ApiError>> {
+        while !self.closed {
+            match self.output.recv().await {
+                Some(IoOutput::Connected(info)) => self.connected = Some(info),
+                Some(IoOutput::MissedEvents(missed)) => self.missed_events = Some(missed),
+                Some(IoOutput::Received(received)) => {
+                    self.closed = received.is_err();
+                    return Some(received);
+                }
+                Some(IoOutput::Closed(frame)) => {
+                    self.close_frame = frame;
+                    self.closed = true;
+                }
+                None => self.closed = true,
+            }
+        }
+        None
+    }
+}
+
+/// Stream of events from a websocket connection.
+///
+/// Returned by `Mattermost::connect_events`. The stream ends when
+/// the connection is closed, and after yielding an error.
+/// Messages that cannot be parsed are skipped.
+pub struct WebsocketEventStream {
+    inner: Pin<Box<dyn Stream<Item = Result<WebsocketEvent, ApiError>> + Send>>,
+}
+
+impl WebsocketEventStream {
+    pub(crate) fn new(connection: Connection) -> Self {
+        Self {
+            inner: Box::pin(stream::unfold(connection, |mut connection| async move {
+                let event = connection.next_event().await?;
+                Some((event, connection))
+            })),
+        }
+    }
+}
+
+impl fmt::Debug for WebsocketEventStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebsocketEventStream")
+            .finish_non_exhaustive()
+    }
+}
+
+impl Stream for WebsocketEventStream {
+    type Item = Result<WebsocketEvent, ApiError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
 /// Handler trait for receiving websocket messages.
 ///
 /// Implement on a struct you create, and pass to
@@ -55,6 +811,29 @@ pub struct WebsocketEvent {
 pub trait WebsocketHandler: Send + Sync {
     /// Function to implement to receive websocket messages.
     async fn callback(&self, _message: WebsocketEvent) {}
+
+    /// Called when reconnecting could not resume the previous
+    /// connection, so events sent in between were lost.
+    ///
+    /// Implement to resync any state kept from events using the REST API.
+    async fn on_missed_events(&self, _missed: MissedEvents) {}
+
+    /// Called when a connection is opened, once its `Hello` event is received.
+    /// Resumed connections have no `Hello` event, and are reported with their first event.
+    ///
+    /// Also called after reconnecting, before `on_missed_events`.
+    async fn on_connect(&self, _info: ConnectionInfo) {}
+
+    /// Called when a connection ends, after `on_error` if it ended with an error.
+    async fn on_disconnect(&self, _reason: DisconnectReason) {}
+
+    /// Called on errors connecting or receiving events.
+    async fn on_error(&self, _error: &ApiError) {}
+
+    /// Called with the text of a message that could not be parsed.
+    ///
+    /// The message is skipped, and the connection stays open.
+    async fn on_parse_error(&self, _text: &str) {}
 }
 
 #[async_trait]
@@ -62,10 +841,30 @@ impl<T: WebsocketHandler> WebsocketHandler for Arc<T> {
     async fn callback(&self, message: WebsocketEvent) {
         self.as_ref().callback(message).await
     }
+
+    async fn on_missed_events(&self, missed: MissedEvents) {
+        self.as_ref().on_missed_events(missed).await
+    }
+
+    async fn on_connect(&self, info: ConnectionInfo) {
+        self.as_ref().on_connect(info).await
+    }
+
+    async fn on_disconnect(&self, reason: DisconnectReason) {
+        self.as_ref().on_disconnect(reason).await
+    }
+
+    async fn on_error(&self, error: &ApiError) {
+        self.as_ref().on_error(error).await
+    }
+
+    async fn on_parse_error(&self, text: &str) {
+        self.as_ref().on_parse_error(text).await
+    }
 }
 
 /// Represents a channel type, as in the Post websocket event payload
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ChannelType {
     /// Represents an open channel type
     #[serde(rename = "O")]
@@ -82,42 +881,114 @@ pub enum ChannelType {
 }
 
 /// Websocket event names.
+///
+/// Payloads that the server sends as JSON-encoded strings are decoded
+/// into their models. Events that this library does not know of
+/// are kept as `Unknown`, so newer servers can still be used.
 #[allow(missing_docs)]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+// the derived implementations are wrapped to handle `Unknown`
+#[serde(remote = "Self")]
 #[serde(tag = "event", content = "data", rename_all = "snake_case")]
 #[non_exhaustive]
 pub enum WebsocketEventType {
-    AddedToTeam(Value),
+    AddedToTeam {
+        team_id: String,
+        user_id: String,
+    },
     AuthenticationChallenge(Value),
-    ChannelConverted(Value),
-    ChannelCreated(Value),
-    ChannelDeleted(Value),
-    ChannelMemberUpdated(Value),
-    ChannelUpdated(Value),
-    ChannelViewed(Value),
-    ConfigChanged(Value),
-    DeleteTeam(Value),
-    DirectAdded(Value),
-    EmojiAdded(Value),
-    EphemeralMessage(Value),
-    GroupAdded(Value),
+    ChannelConverted {
+        channel_id: String,
+    },
+    ChannelCreated {
+        channel_id: String,
+        team_id: String,
+    },
+    ChannelDeleted {
+        channel_id: String,
+        delete_at: i64,
+    },
+    ChannelMemberUpdated {
+        #[serde(rename = "channelMember", with = "serde_nested_json")]
+        channel_member: ChannelMember,
+    },
+    ChannelUpdated {
+        #[serde(with = "serde_nested_json")]
+        channel: ChannelInformation,
+    },
+    ChannelViewed {
+        channel_id: String,
+    },
+    ConfigChanged {
+        config: HashMap<String, String>,
+    },
+    DeleteTeam {
+        #[serde(with = "serde_nested_json")]
+        team: TeamInformation,
+    },
+    DirectAdded {
+        creator_id: String,
+        teammate_id: String,
+    },
+    EmojiAdded {
+        #[serde(with = "serde_nested_json")]
+        emoji: Emoji,
+    },
+    EphemeralMessage {
+        #[serde(with = "serde_nested_json")]
+        post: Post,
+    },
+    GroupAdded {
+        #[serde(with = "serde_nested_json")]
+        teammate_ids: Vec<String>,
+    },
     Hello {
         connection_id: String,
         server_version: String,
     },
-    LeaveTeam(Value),
-    LicenseChanged(Value),
-    MemberroleUpdated(Value),
-    NewUser(Value),
-    PluginDisabled(Value),
-    PluginEnabled(Value),
-    PluginStatusesChanged(Value),
-    PostDeleted(Value),
+    LeaveTeam {
+        team_id: String,
+        user_id: String,
+    },
+    LicenseChanged {
+        license: HashMap<String, String>,
+    },
+    MemberroleUpdated {
+        #[serde(with = "serde_nested_json")]
+        member: TeamMember,
+    },
+    NewUser {
+        user_id: String,
+    },
+    PluginDisabled {
+        manifest: PluginManifest,
+    },
+    PluginEnabled {
+        manifest: PluginManifest,
+    },
+    PluginStatusesChanged {
+        plugin_statuses: Vec<PluginStatus>,
+    },
+    PostDeleted {
+        #[serde(with = "serde_nested_json")]
+        post: Post,
+        delete_by: Option<String>,
+    },
     PostEdited {
         #[serde(with = "serde_nested_json")]
         post: Post,
     },
-    PostUnread(Value),
+    PostUnread {
+        channel_id: Option<String>,
+        team_id: Option<String>,
+        post_id: Option<String>,
+        msg_count: Option<i64>,
+        msg_count_root: Option<i64>,
+        mention_count: Option<i64>,
+        mention_count_root: Option<i64>,
+        urgent_mention_count: Option<i64>,
+        last_viewed_at: Option<i64>,
+    },
     Posted {
         channel_display_name: String,
         channel_name: String,
@@ -127,9 +998,18 @@ pub enum WebsocketEventType {
         #[serde(with = "serde_nested_json")]
         post: Post,
     },
-    PreferenceChanged(Value),
-    PreferencesChanged(Value),
-    PreferencesDeleted(Value),
+    PreferenceChanged {
+        #[serde(with = "serde_nested_json")]
+        preference: Preference,
+    },
+    PreferencesChanged {
+        #[serde(with = "serde_nested_json")]
+        preferences: Vec<Preference>,
+    },
+    PreferencesDeleted {
+        #[serde(with = "serde_nested_json")]
+        preferences: Vec<Preference>,
+    },
     ReactionAdded {
         #[serde(with = "serde_nested_json")]
         reaction: Reaction,
@@ -139,19 +1019,279 @@ pub enum WebsocketEventType {
         reaction: Reaction,
     },
     Response(Value),
-    RoleUpdated(Value),
-    StatusChange(Value),
+    RoleUpdated {
+        #[serde(with = "serde_nested_json")]
+        role: Role,
+    },
+    StatusChange {
+        status: String,
+        user_id: String,
+    },
     Typing {
         parent_id: String,
         user_id: String,
     },
-    UpdateTeam(Value),
-    UserAdded(Value),
-    UserRemoved(Value),
-    UserRoleUpdated(Value),
-    UserUpdated(Value),
-    DialogOpened(Value),
-    ThreadUpdated(Value),
-    ThreadFollowChanged(Value),
-    ThreadReadChanged(Value),
+    UpdateTeam {
+        #[serde(with = "serde_nested_json")]
+        team: TeamInformation,
+    },
+    /// A user was added to the channel in the broadcast
+    UserAdded {
+        team_id: Option<String>,
+        user_id: String,
+    },
+    /// A user was removed from a channel; `channel_id` is set when the
+    /// event is sent to the removed user, `user_id` otherwise
+    UserRemoved {
+        channel_id: Option<String>,
+        user_id: Option<String>,
+        remover_id: String,
+    },
+    UserRoleUpdated {
+        user_id: String,
+        roles: String,
+    },
+    UserUpdated {
+        user: User,
+    },
+    DialogOpened {
+        #[serde(with = "serde_nested_json")]
+        dialog: OpenDialog,
+    },
+    ThreadUpdated {
+        #[serde(with = "serde_nested_json")]
+        thread: UserThread,
+    },
+    ThreadFollowChanged {
+        thread_id: String,
+        state: bool,
+        reply_count: i64,
+    },
+    /// Thread fields are unset when all threads were marked as read
+    ThreadReadChanged {
+        thread_id: Option<String>,
+        channel_id: Option<String>,
+        timestamp: i64,
+        unread_mentions: Option<i64>,
+        unread_replies: Option<i64>,
+        previous_unread_mentions: Option<i64>,
+        previous_unread_replies: Option<i64>,
+    },
+    /// Event of a type not known to this library
+    #[serde(skip)]
+    Unknown {
+        event: String,
+        data: Value,
+    },
+}
+
+impl WebsocketEventType {
+    /// Name of the event type, as sent by the server.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::AddedToTeam { .. } => "added_to_team",
+            Self::AuthenticationChallenge { .. } => "authentication_challenge",
+            Self::ChannelConverted { .. } => "channel_converted",
+            Self::ChannelCreated { .. } => "channel_created",
+            Self::ChannelDeleted { .. } => "channel_deleted",
+            Self::ChannelMemberUpdated { .. } => "channel_member_updated",
+            Self::ChannelUpdated { .. } => "channel_updated",
+            Self::ChannelViewed { .. } => "channel_viewed",
+            Self::ConfigChanged { .. } => "config_changed",
+            Self::DeleteTeam { .. } => "delete_team",
+            Self::DirectAdded { .. } => "direct_added",
+            Self::EmojiAdded { .. } => "emoji_added",
+            Self::EphemeralMessage { .. } => "ephemeral_message",
+            Self::GroupAdded { .. } => "group_added",
+            Self::Hello { .. } => "hello",
+            Self::LeaveTeam { .. } => "leave_team",
+            Self::LicenseChanged { .. } => "license_changed",
+            Self::MemberroleUpdated { .. } => "memberrole_updated",
+            Self::NewUser { .. } => "new_user",
+            Self::PluginDisabled { .. } => "plugin_disabled",
+            Self::PluginEnabled { .. } => "plugin_enabled",
+            Self::PluginStatusesChanged { .. } => "plugin_statuses_changed",
+            Self::PostDeleted { .. } => "post_deleted",
+            Self::PostEdited { .. } => "post_edited",
+            Self::PostUnread { .. } => "post_unread",
+            Self::Posted { .. } => "posted",
+            Self::PreferenceChanged { .. } => "preference_changed",
+            Self::PreferencesChanged { .. } => "preferences_changed",
+            Self::PreferencesDeleted { .. } => "preferences_deleted",
+            Self::ReactionAdded { .. } => "reaction_added",
+            Self::ReactionRemoved { .. } => "reaction_removed",
+            Self::Response { .. } => "response",
+            Self::RoleUpdated { .. } => "role_updated",
+            Self::StatusChange { .. } => "status_change",
+            Self::Typing { .. } => "typing",
+            Self::UpdateTeam { .. } => "update_team",
+            Self::UserAdded { .. } => "user_added",
+            Self::UserRemoved { .. } => "user_removed",
+            Self::UserRoleUpdated { .. } => "user_role_updated",
+            Self::UserUpdated { .. } => "user_updated",
+            Self::DialogOpened { .. } => "dialog_opened",
+            Self::ThreadUpdated { .. } => "thread_updated",
+            Self::ThreadFollowChanged { .. } => "thread_follow_changed",
+            Self::ThreadReadChanged { .. } => "thread_read_changed",
+            Self::Unknown { event, .. } => event,
+        }
+    }
+
+    /// If `event` is the name of a known event type.
+    fn is_known(event: &str) -> bool {
+        /// Error recording if deserializing failed on the event name.
+        #[derive(Debug)]
+        struct Probe {
+            unknown_variant: bool,
+        }
+
+        impl fmt::Display for Probe {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("probe")
+            }
+        }
+
+        impl std::error::Error for Probe {}
+
+        impl de::Error for Probe {
+            fn custom<T: fmt::Display>(_msg: T) -> Self {
+                Self {
+                    unknown_variant: false,
+                }
+            }
+
+            fn unknown_variant(_variant: &str, _expected: &'static [&'static str]) -> Self {
+                Self {
+                    unknown_variant: true,
+                }
+            }
+        }
+
+        let tag = MapDeserializer::<_, Probe>::new(std::iter::once(("event", event)));
+        !matches!(
+            Self::deserialize(tag),
+            Err(Probe {
+                unknown_variant: true
+            })
+        )
+    }
+}
+
+impl Serialize for WebsocketEventType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Unknown { event, data } => {
+                let mut state = serializer.serialize_struct("WebsocketEventType", 2)?;
+                state.serialize_field("event", event)?;
+                state.serialize_field("data", data)?;
+                state.end()
+            }
+            _ => Self::serialize(self, serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for WebsocketEventType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct RawEvent {
+            event: String,
+            #[serde(default)]
+            data: Value,
+        }
+
+        let RawEvent { event, data } = RawEvent::deserialize(deserializer)?;
+        if !Self::is_known(&event) {
+            return Ok(Self::Unknown { event, data });
+        }
+        Self::deserialize(json!({ "event": event, "data": data })).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WebsocketEvent, WebsocketEventType, WebsocketReply, WebsocketSender};
+    use async_tungstenite::tungstenite::Message;
+    use serde_json::Value;
+
+    #[test]
+    fn plain_payloads_are_parsed() {
+        let event: WebsocketEvent = serde_json::from_str(
+            r#"{"event":"status_change","data":{"status":"away","user_id":"u1"},
+            "broadcast":{"omit_users":null,"user_id":"u1","channel_id":"","team_id":""},"seq":4}"#,
+        )
+        .unwrap();
+
+        let WebsocketEventType::StatusChange { status, user_id } = event.event else {
+            panic!("Expected a status_change event")
+        };
+        assert_eq!(status, "away");
+        assert_eq!(user_id, "u1");
+    }
+
+    #[test]
+    fn unknown_events_are_kept() {
+        let text = r#"{"event":"some_future_event","data":{"key":"value"},"broadcast":{"omit_users":null,"user_id":"u1","channel_id":"","team_id":""},"seq":4}"#;
+        let event: WebsocketEvent = serde_json::from_str(text).unwrap();
+
+        let WebsocketEventType::Unknown { event: name, data } = &event.event else {
+            panic!("Expected an unknown event")
+        };
+        assert_eq!(name, "some_future_event");
+        assert_eq!(data["key"], "value");
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::from_str::<Value>(text).unwrap()
+        );
+    }
+
+    #[test]
+    fn invalid_payloads_of_known_events_are_errors() {
+        let result = serde_json::from_str::<WebsocketEvent>(
+            r#"{"event":"status_change","data":{"status":4},
+            "broadcast":{"omit_users":null,"user_id":"u1","channel_id":"","team_id":""},"seq":4}"#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn nested_json_payloads_are_parsed() {
+        let event: WebsocketEvent = serde_json::from_str(
+            r#"{"event":"post_deleted","data":{"delete_by":"u2","post":"{\"id\":\"p1\",\"create_at\":1,\"update_at\":2,\"delete_at\":3,\"edit_at\":0,\"user_id\":\"u1\",\"channel_id\":\"c1\",\"root_id\":\"\",\"original_id\":\"\",\"message\":\"hi\",\"type\":\"\",\"props\":{},\"pending_post_id\":\"\"}"},
+            "broadcast":{"omit_users":null,"user_id":"","channel_id":"c1","team_id":""},"seq":5}"#,
+        )
+        .unwrap();
+
+        let WebsocketEventType::PostDeleted { post, delete_by } = event.event else {
+            panic!("Expected a post_deleted event")
+        };
+        assert_eq!(post.id, "p1");
+        assert_eq!(delete_by.as_deref(), Some("u2"));
+    }
+
+    #[tokio::test]
+    async fn replies_are_matched_to_actions_by_seq() {
+        let sender = WebsocketSender::default();
+        let (_, mut outgoing) = sender.attach();
+
+        let action = tokio::spawn({
+            let sender = sender.clone();
+            async move { sender.get_statuses_by_ids(&["u1"]).await }
+        });
+        let Some(Message::Text(text)) = outgoing.recv().await else {
+            panic!("Expected a text message")
+        };
+        let sent: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(sent["action"], "get_statuses_by_ids");
+
+        sender.handle_reply(WebsocketReply {
+            status: "OK".to_string(),
+            seq_reply: sent["seq"].as_u64().unwrap(),
+            data: Some(serde_json::json!({ "u1": "online" })),
+            error: None,
+        });
+
+        let statuses = action.await.unwrap().unwrap();
+        assert_eq!(statuses["u1"], "online");
+    }
 }